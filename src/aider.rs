@@ -0,0 +1,82 @@
+use std::process::Command;
+
+/// Arguments for a single non-interactive aider run on `files`
+pub fn aider_args(task: &str, files: &[String], extra_args: &[String]) -> Vec<String> {
+    let mut args = vec!["--message".to_string(), task.to_string()];
+    args.extend(extra_args.iter().cloned());
+    args.extend(files.iter().cloned());
+    args
+}
+
+fn shell_quote(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:@%+,".contains(c));
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+pub fn format_command(program: &str, args: &[String]) -> String {
+    std::iter::once(program)
+        .chain(args.iter().map(|a| a.as_str()))
+        .map(shell_quote)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Run aider and return its exit code
+pub fn run_aider(program: &str, args: &[String]) -> Result<i32, Box<dyn std::error::Error>> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| format!("Error running {}: {}", program, e))?;
+    // Killed by a signal has no exit code
+    Ok(status.code().unwrap_or(1))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn runs_stub_aider_with_args_and_exit_status() {
+        let dir = std::env::temp_dir().join(format!("luckyshot-aider-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let argv_file = dir.join("argv");
+        let stub = dir.join("aider");
+        std::fs::write(
+            &stub,
+            format!(
+                "#!/bin/sh\nprintf '%s\\n' \"$@\" > '{}'\nexit 3\n",
+                argv_file.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&stub, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let files = vec!["src/main.rs".to_string(), "src/a file.rs".to_string()];
+        let extra_args = vec!["--model".to_string(), "gpt-4o".to_string()];
+        let args = aider_args("make the background 'green'", &files, &extra_args);
+        let code = run_aider(stub.to_str().unwrap(), &args).unwrap();
+
+        let argv = std::fs::read_to_string(&argv_file).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(code, 3);
+        assert_eq!(
+            argv.lines().collect::<Vec<_>>(),
+            vec![
+                "--message",
+                "make the background 'green'",
+                "--model",
+                "gpt-4o",
+                "src/main.rs",
+                "src/a file.rs",
+            ]
+        );
+    }
+}
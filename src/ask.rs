@@ -0,0 +1,158 @@
+use crate::cache::EmbeddingCache;
+use crate::pack::{Pack, PackFormat, PackOptions};
+use crate::scan::FileVectorStore;
+use crate::search::{SearchOptions, WeightedQuery};
+use regex::Regex;
+use std::io::Write;
+
+pub const ASK_SYSTEM_PROMPT: &str = "You answer questions about a codebase using only the \
+excerpts provided. Each excerpt is headed by its file path and line range. Support every claim \
+with a citation of the lines it rests on, written as path:start-end (for example \
+src/main.rs:10-24), using the line numbers of the excerpt headers. If the excerpts don't \
+answer the question, say so rather than guessing.";
+
+pub struct AskOptions {
+    pub max_tokens: usize,     // Budget for the packed excerpts
+    pub system_prompt: String, // Instructions for the chat model
+    pub show_context: bool,    // Print the packed excerpts to stderr
+}
+
+/// A `path:start-end` reference in the answer, and whether the lines were in the context
+struct Citation {
+    filename: String,
+    start_line: usize,
+    end_line: usize,
+    retrieved: bool,
+}
+
+impl std::fmt::Display for Citation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.start_line == self.end_line {
+            write!(f, "{}:{}", self.filename, self.start_line)
+        } else {
+            write!(f, "{}:{}-{}", self.filename, self.start_line, self.end_line)
+        }
+    }
+}
+
+/// Every distinct `path:line` or `path:start-end` cited in the answer, each checked
+/// against the line ranges of the pack
+fn check_citations(answer: &str, pack: &Pack) -> Vec<Citation> {
+    // A path needs an extension, so times like 12:30 aren't taken for citations
+    let pattern = Regex::new(r"([\w./-]*[A-Za-z][\w./-]*\.\w+):(\d+)(?:-(\d+))?").unwrap();
+    let mut citations: Vec<Citation> = Vec::new();
+    for captures in pattern.captures_iter(answer) {
+        let filename = captures[1].trim_start_matches("./").to_string();
+        let start_line: usize = captures[2].parse().unwrap_or(0);
+        let end_line: usize = captures
+            .get(3)
+            .and_then(|m| m.as_str().parse().ok())
+            .unwrap_or(start_line);
+        if citations
+            .iter()
+            .any(|c| c.filename == filename && c.start_line == start_line && c.end_line == end_line)
+        {
+            continue;
+        }
+        let retrieved = pack.ranges.iter().any(|(packed, first, last)| {
+            *packed == filename
+                && *first <= start_line
+                && start_line <= end_line
+                && end_line <= *last
+        });
+        citations.push(Citation {
+            filename,
+            start_line,
+            end_line,
+            retrieved,
+        });
+    }
+    citations
+}
+
+/// Answer the question from the best chunks of the index, packed into `max_tokens`.
+/// The answer streams to stdout. The context, citation check and cost go to stderr,
+/// so the answer can be piped on its own.
+pub async fn ask(
+    store: &FileVectorStore,
+    question: &str,
+    api_key: &str,
+    search_options: &SearchOptions,
+    options: &AskOptions,
+    embeddings: &EmbeddingCache,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let max_tokens = options.max_tokens;
+    let pack_options = PackOptions {
+        max_tokens,
+        format: PackFormat::Markdown,
+    };
+    // Packing ranks chunks rather than files, so the count is of chunks
+    let search_options = SearchOptions {
+        pack: Some(pack_options.clone()),
+        ..search_options.clone()
+    };
+    let queries = [WeightedQuery::new(question)];
+    let matches =
+        crate::search::rank_files(store, &queries, api_key, &search_options, embeddings).await?;
+    if matches.is_empty() {
+        return Err("No relevant files found for the question".into());
+    }
+    let pack = crate::pack::pack(store, &matches, &pack_options);
+    if pack.ranges.is_empty() {
+        return Err(format!("No excerpt fits in {} tokens", max_tokens).into());
+    }
+    if options.show_context {
+        eprintln!("{}", pack.text);
+    }
+    if !pack.skipped.is_empty() {
+        eprintln!("Didn't fit in {} tokens:", max_tokens);
+        for s in &pack.skipped {
+            eprintln!("  {}", s);
+        }
+    }
+
+    let prompt = format!("{}# Question\n\n{}", pack.text, question);
+    let mut stdout = std::io::stdout();
+    let (answer, usage) = crate::openai::stream_openai_chat_completion(
+        &prompt,
+        &options.system_prompt,
+        api_key,
+        |delta| {
+            print!("{}", delta);
+            let _ = stdout.flush();
+        },
+    )
+    .await?;
+    if !answer.ends_with('\n') {
+        println!();
+    }
+
+    let citations = check_citations(&answer, &pack);
+    let outside: Vec<&Citation> = citations.iter().filter(|c| !c.retrieved).collect();
+    if citations.is_empty() {
+        eprintln!("\nThe answer cites no lines of the context");
+    } else if outside.is_empty() {
+        eprintln!(
+            "\nAll {} citation(s) are within the retrieved context",
+            citations.len()
+        );
+    } else {
+        eprintln!(
+            "\n{} of {} citation(s) are outside the retrieved context:",
+            outside.len(),
+            citations.len()
+        );
+        for citation in outside {
+            eprintln!("  {}", citation);
+        }
+    }
+
+    let config = crate::config::load_config();
+    let cost = crate::estimate::chat_cost(crate::openai::CHAT_MODEL, &usage, &config)
+        .map_or("unknown".to_string(), |cost| format!("${:.4}", cost));
+    eprintln!(
+        "Ask: {} prompt + {} completion tokens, cost {}",
+        usage.prompt_tokens, usage.completion_tokens, cost
+    );
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use crate::tokenizer::get_tokenizer;
-use bm25::{DefaultTokenizer, EmbedderBuilder};
+use bm25::{DefaultTokenizer, EmbedderBuilder, Tokenizer};
 
 #[derive(Debug)]
 pub struct Bm25Vector {
@@ -18,3 +18,30 @@ pub fn create_bm25_vector(text: &str, avgdl: f32) -> Bm25Vector {
         values: embedding.values().cloned().collect(),
     }
 }
+
+// Splits text joined by `TOKEN_SEPARATOR` back into the tokens it was made of
+#[derive(Default)]
+struct StoredTokens;
+
+const TOKEN_SEPARATOR: &str = "\n";
+
+impl Tokenizer for StoredTokens {
+    fn tokenize(&self, input_text: &str) -> Vec<String> {
+        input_text
+            .split(TOKEN_SEPARATOR)
+            .filter(|t| !t.is_empty())
+            .map(|t| t.to_string())
+            .collect()
+    }
+}
+
+/// The vector `create_bm25_vector` gives for a text with these tokens, for
+/// recomputing stored files' vectors when the average document length changes
+pub fn create_bm25_vector_from_tokens(tokens: &[String], avgdl: f32) -> Bm25Vector {
+    let embedder = EmbedderBuilder::<u32, StoredTokens>::with_avgdl(avgdl).build();
+    let embedding = embedder.embed(&tokens.join(TOKEN_SEPARATOR));
+    Bm25Vector {
+        indices: embedding.indices().cloned().collect(),
+        values: embedding.values().cloned().collect(),
+    }
+}
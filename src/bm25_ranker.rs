@@ -38,6 +38,5 @@ pub fn rank_documents(
         create_embedding_from_indices_and_values(query_embedding.indices, query_embedding.values);
 
     // Get matches sorted by score
-    let matches = scorer.matches(&query_embedding);
-    matches
+    scorer.matches(&query_embedding)
 }
@@ -0,0 +1,154 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Mutex, OnceLock};
+
+/// Key-value cache persisted as a JSON file beside the vectors file
+pub struct JsonCache<V> {
+    path: String,
+    entries: HashMap<String, V>,
+    dirty: bool,
+}
+
+impl<V: Serialize + DeserializeOwned + Clone> JsonCache<V> {
+    /// Load the cache, starting empty if the file is missing or unreadable
+    pub fn load(path: &str) -> Self {
+        let entries = fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        JsonCache {
+            path: path.to_string(),
+            entries,
+            dirty: false,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<V> {
+        self.entries.get(key).cloned()
+    }
+
+    pub fn insert(&mut self, key: String, value: V) {
+        self.entries.insert(key, value);
+        self.dirty = true;
+    }
+
+    /// Keep only the `max_entries` entries with the highest `recency`
+    pub fn evict(&mut self, max_entries: usize, recency: impl Fn(&V) -> u64) {
+        if self.entries.len() <= max_entries {
+            return;
+        }
+        let mut keys: Vec<(u64, String)> = self
+            .entries
+            .iter()
+            .map(|(key, value)| (recency(value), key.clone()))
+            .collect();
+        keys.sort_by(|a, b| b.cmp(a));
+        for (_, key) in keys.into_iter().skip(max_entries) {
+            self.entries.remove(&key);
+        }
+        self.dirty = true;
+    }
+
+    pub fn save(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.dirty {
+            fs::write(&self.path, serde_json::to_string(&self.entries)?)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+/// Stable hash of the parts, for use as a cache key
+pub fn hash_key(parts: &[&str]) -> String {
+    let mut hasher = sha1_smol::Sha1::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update(&[0]);
+    }
+    hasher.digest().to_string()
+}
+
+pub const EMBEDDING_CACHE_FILE: &str = ".luckyshot.embedding.cache.v1";
+pub const CHUNK_EMBEDDING_CACHE_FILE: &str = ".luckyshot.chunk.cache.v1";
+
+// Query embeddings kept, about 20 KB each, the least recently used are dropped first
+pub const MAX_QUERY_EMBEDDINGS: usize = 1000;
+
+// A cached embedding's last use is only rewritten when it is older than this
+const USED_RESOLUTION_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CachedEmbedding {
+    embedding: Vec<f32>,
+    used: u64, // Seconds since the epoch of the last lookup
+}
+
+/// Embeddings keyed by model and text, so the same text is only paid for once. The
+/// file is loaded on the first lookup, and lookups take `&self`, so one cache serves
+/// every search of a command or server and is saved once.
+pub struct EmbeddingCache {
+    path: String,
+    max_entries: usize, // 0 for unlimited
+    cache: OnceLock<Mutex<JsonCache<CachedEmbedding>>>,
+}
+
+impl EmbeddingCache {
+    pub fn load(path: &str, max_entries: usize) -> Self {
+        EmbeddingCache {
+            path: path.to_string(),
+            max_entries,
+            cache: OnceLock::new(),
+        }
+    }
+
+    fn entries(&self) -> &Mutex<JsonCache<CachedEmbedding>> {
+        self.cache
+            .get_or_init(|| Mutex::new(JsonCache::load(&self.path)))
+    }
+
+    /// Embedding of `text`, from the cache when it was embedded before
+    pub async fn embed(
+        &self,
+        text: &str,
+        api_key: &str,
+    ) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        let key = hash_key(&[crate::openai::EMBEDDING_MODEL, text]);
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        {
+            let mut entries = self.entries().lock().unwrap();
+            if let Some(mut cached) = entries.get(&key) {
+                if now.saturating_sub(cached.used) > USED_RESOLUTION_SECS {
+                    cached.used = now;
+                    entries.insert(key, cached.clone());
+                }
+                return Ok(cached.embedding);
+            }
+        }
+        let embedding = crate::openai::get_embedding(text, api_key).await?;
+        self.entries().lock().unwrap().insert(
+            key,
+            CachedEmbedding {
+                embedding: embedding.clone(),
+                used: now,
+            },
+        );
+        Ok(embedding)
+    }
+
+    /// Write the cache if it was loaded and changed, dropping the least recently used
+    /// embeddings over the limit
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(entries) = self.cache.get() else {
+            return Ok(());
+        };
+        let mut entries = entries.lock().unwrap();
+        if self.max_entries > 0 {
+            entries.evict(self.max_entries, |cached| cached.used);
+        }
+        entries.save()
+    }
+}
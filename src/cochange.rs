@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+pub const COCHANGE_CACHE_FILE: &str = ".luckyshot.cochange.v1";
+
+// How far back to mine the history
+const MAX_COMMITS: usize = 5000;
+// Larger commits are mostly renames, reformats and vendoring, not related changes
+const MAX_COMMIT_FILES: usize = 30;
+// Pairs changed together fewer times than this are coincidence
+const MIN_COCHANGES: u32 = 2;
+
+/// How often files changed together, mined from `git log`
+#[derive(Serialize, Deserialize, Default)]
+pub struct CoChangeGraph {
+    pub head: String,                                 // Commit the graph was mined at
+    pub commits: HashMap<String, u32>,                // Commits touching each file
+    pub pairs: HashMap<String, HashMap<String, u32>>, // Commits touching both files
+}
+
+impl CoChangeGraph {
+    pub fn build(head: String) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut graph = CoChangeGraph {
+            head,
+            ..Default::default()
+        };
+        for files in crate::git::commit_changes(MAX_COMMITS)? {
+            if files.len() > MAX_COMMIT_FILES {
+                continue;
+            }
+            for a in &files {
+                *graph.commits.entry(a.clone()).or_insert(0) += 1;
+                for b in files.iter().filter(|b| *b != a) {
+                    *graph
+                        .pairs
+                        .entry(a.clone())
+                        .or_default()
+                        .entry(b.clone())
+                        .or_insert(0) += 1;
+                }
+            }
+        }
+        Ok(graph)
+    }
+
+    /// Files that changed together with `filename`, strongest first. Strength is the
+    /// share of `filename`'s commits that also touched the neighbor.
+    pub fn neighbors(&self, filename: &str) -> Vec<(String, f32)> {
+        let (Some(pairs), Some(&commits)) = (self.pairs.get(filename), self.commits.get(filename))
+        else {
+            return Vec::new();
+        };
+        let mut neighbors: Vec<(String, f32)> = pairs
+            .iter()
+            .filter(|(_, &count)| count >= MIN_COCHANGES)
+            .map(|(neighbor, &count)| (neighbor.clone(), count as f32 / commits as f32))
+            .collect();
+        neighbors.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then_with(|| a.0.cmp(&b.0)));
+        neighbors
+    }
+}
+
+/// The co-change graph for the current HEAD, mined again only when HEAD has moved
+/// since it was cached
+pub fn load_graph() -> Result<CoChangeGraph, Box<dyn std::error::Error>> {
+    let head = crate::git::resolve_rev("HEAD")?;
+    let cached: Option<CoChangeGraph> = fs::read_to_string(COCHANGE_CACHE_FILE)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok());
+    if let Some(graph) = cached.filter(|g| g.head == head) {
+        return Ok(graph);
+    }
+
+    let graph = CoChangeGraph::build(head)?;
+    if let Err(e) = fs::write(COCHANGE_CACHE_FILE, serde_json::to_string(&graph)?) {
+        eprintln!("Error writing co-change cache: {}", e);
+    }
+    Ok(graph)
+}
@@ -0,0 +1,66 @@
+use crate::search::{Aggregation, Fusion};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+pub const CONFIG_FILE: &str = ".luckyshot.config.json";
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct ChatPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+/// Project level settings read from `.luckyshot.config.json`
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    /// Embedding price in USD per million tokens, keyed by model name
+    pub embedding_prices: HashMap<String, f64>,
+    /// Chat model prices in USD per million input and output tokens, keyed by model name
+    pub chat_prices: HashMap<String, ChatPrice>,
+    /// Score multipliers like `rs=1.2` or `path:src/**=1.1`, same as `--prefer`
+    pub prefer: Vec<String>,
+    /// Score multiplier for test files, same as `--test-weight`
+    pub test_weight: Option<f32>,
+    /// Default `--bm25-scale`
+    pub bm25_scale: Option<f32>,
+    /// Default `--rag-scale`
+    pub rag_scale: Option<f32>,
+    /// Default `--fusion`
+    pub fusion: Option<Fusion>,
+    /// Default `--aggregation`
+    pub aggregation: Option<Aggregation>,
+    /// Default scan `--chunk-size`
+    pub chunk_size: Option<usize>,
+    /// Default scan `--chunk-overlap`
+    pub chunk_overlap: Option<usize>,
+}
+
+pub fn load_config() -> Config {
+    match fs::read_to_string(CONFIG_FILE) {
+        Ok(content) => match serde_json::from_str(&content) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Error parsing config file: {}", e);
+                Config::default()
+            }
+        },
+        Err(_) => Config::default(),
+    }
+}
+
+/// Set `values` in the config file, keeping everything else in it as it is
+pub fn update_config(
+    values: serde_json::Map<String, serde_json::Value>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config: serde_json::Map<String, serde_json::Value> =
+        match fs::read_to_string(CONFIG_FILE) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| format!("Error parsing config file: {}", e))?,
+            Err(_) => serde_json::Map::new(),
+        };
+    config.extend(values);
+    fs::write(CONFIG_FILE, serde_json::to_string_pretty(&config)? + "\n")?;
+    Ok(())
+}
@@ -0,0 +1,370 @@
+use crate::expand::ExpansionKind;
+use crate::scan::{FileVectorStore, SourceFile};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+
+// A hit's own imports are usually more relevant than the files importing it
+const DEPENDENT_STRENGTH: f32 = 0.8;
+
+const JS_EXTENSIONS: &[&str] = &["ts", "tsx", "js", "jsx", "mjs", "cjs"];
+
+struct Patterns {
+    rust_mod: Regex,
+    rust_path: Regex,
+    rust_group: Regex,
+    js_import: Regex,
+    python_from: Regex,
+    python_import: Regex,
+    go_block: Regex,
+    go_single: Regex,
+    go_path: Regex,
+}
+
+impl Patterns {
+    fn new() -> Self {
+        Patterns {
+            rust_mod: Regex::new(
+                r"(?m)^\s*(?:pub(?:\([^)]*\))?\s+)?mod\s+([A-Za-z_][A-Za-z0-9_]*)\s*;",
+            )
+            .unwrap(),
+            rust_path: Regex::new(r"\b(crate|super|self)((?:::[A-Za-z_][A-Za-z0-9_]*)+)").unwrap(),
+            rust_group: Regex::new(r"\b(crate|super|self)::\{([^}]*)\}").unwrap(),
+            js_import: Regex::new(
+                r#"(?:\bfrom\s*|\bimport\s*|\b(?:require|import)\s*\(\s*)['"](\.[^'"]*)['"]"#,
+            )
+            .unwrap(),
+            python_from: Regex::new(r"(?m)^\s*from\s+(\.*)([A-Za-z0-9_.]*)\s+import\s+([^#\n]+)")
+                .unwrap(),
+            python_import: Regex::new(r"(?m)^\s*import\s+([A-Za-z0-9_., ]+)").unwrap(),
+            go_block: Regex::new(r"(?s)\bimport\s*\((.*?)\)").unwrap(),
+            go_single: Regex::new(r#"\bimport\s+(?:[A-Za-z_.]+\s+)?"([^"]+)""#).unwrap(),
+            go_path: Regex::new(r#""([^"]+)""#).unwrap(),
+        }
+    }
+}
+
+fn parent(path: &str) -> &str {
+    path.rfind('/').map_or("", |i| &path[..i])
+}
+
+/// Join `relative` onto `dir`, resolving `.` and `..`
+fn join(dir: &str, relative: &str) -> String {
+    let mut parts: Vec<&str> = dir.split('/').filter(|p| !p.is_empty()).collect();
+    for part in relative.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+fn first_indexed(candidates: &[String], indexed: &HashSet<&str>) -> Option<String> {
+    candidates
+        .iter()
+        .find(|c| indexed.contains(c.as_str()))
+        .cloned()
+}
+
+/// Directory holding a Rust file's submodules
+fn rust_module_dir(path: &str) -> String {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    match name {
+        "main.rs" | "lib.rs" | "mod.rs" => parent(path).to_string(),
+        _ => path.trim_end_matches(".rs").to_string(),
+    }
+}
+
+/// Nearest ancestor directory with a crate root, or the file's directory
+fn rust_crate_root(path: &str, indexed: &HashSet<&str>) -> String {
+    let mut dir = parent(path);
+    loop {
+        if ["main.rs", "lib.rs"]
+            .iter()
+            .any(|root| indexed.contains(join(dir, root).as_str()))
+        {
+            return dir.to_string();
+        }
+        if dir.is_empty() {
+            return parent(path).to_string();
+        }
+        dir = parent(dir);
+    }
+}
+
+// The longest prefix of the module path that is a file, as `a/b.rs` or `a/b/mod.rs`
+fn resolve_rust_path(base: &str, segments: &[&str], indexed: &HashSet<&str>) -> Option<String> {
+    (1..=segments.len()).rev().find_map(|n| {
+        let module = join(base, &segments[..n].join("/"));
+        first_indexed(
+            &[format!("{}.rs", module), format!("{}/mod.rs", module)],
+            indexed,
+        )
+    })
+}
+
+fn rust_dependencies(
+    path: &str,
+    contents: &str,
+    indexed: &HashSet<&str>,
+    patterns: &Patterns,
+) -> Vec<String> {
+    let module_dir = rust_module_dir(path);
+    let crate_root = rust_crate_root(path, indexed);
+    let base = |anchor: &str| match anchor {
+        "crate" => crate_root.clone(),
+        "super" => parent(&module_dir).to_string(),
+        _ => module_dir.clone(),
+    };
+    let mut dependencies = Vec::new();
+
+    for capture in patterns.rust_mod.captures_iter(contents) {
+        dependencies.extend(resolve_rust_path(&module_dir, &[&capture[1]], indexed));
+    }
+    for capture in patterns.rust_path.captures_iter(contents) {
+        let segments: Vec<&str> = capture[2].split("::").filter(|s| !s.is_empty()).collect();
+        dependencies.extend(resolve_rust_path(&base(&capture[1]), &segments, indexed));
+    }
+    // use crate::{a, b::c};
+    for capture in patterns.rust_group.captures_iter(contents) {
+        for item in capture[2].split(',') {
+            let segments: Vec<&str> = item.trim().split("::").map(|s| s.trim()).collect();
+            dependencies.extend(resolve_rust_path(&base(&capture[1]), &segments, indexed));
+        }
+    }
+
+    dependencies
+}
+
+fn js_dependencies(
+    path: &str,
+    contents: &str,
+    indexed: &HashSet<&str>,
+    patterns: &Patterns,
+) -> Vec<String> {
+    patterns
+        .js_import
+        .captures_iter(contents)
+        .filter_map(|capture| {
+            let module = join(parent(path), &capture[1]);
+            let mut candidates = vec![module.clone()];
+            // TypeScript imports compiled names, `./a.js` is `./a.ts` in the source
+            if let Some(stem) = module.strip_suffix(".js") {
+                candidates.extend(["ts", "tsx"].iter().map(|e| format!("{}.{}", stem, e)));
+            }
+            for extension in JS_EXTENSIONS {
+                candidates.push(format!("{}.{}", module, extension));
+                candidates.push(format!("{}/index.{}", module, extension));
+            }
+            first_indexed(&candidates, indexed)
+        })
+        .collect()
+}
+
+// `a/b.py` or `a/b/__init__.py`, under the file's directory or any directory above it
+fn resolve_python_module(path: &str, module: &str, indexed: &HashSet<&str>) -> Option<String> {
+    let relative = module.replace('.', "/");
+    let mut dir = parent(path);
+    loop {
+        let module_path = join(dir, &relative);
+        let found = first_indexed(
+            &[
+                format!("{}.py", module_path),
+                format!("{}/__init__.py", module_path),
+            ],
+            indexed,
+        );
+        if found.is_some() || dir.is_empty() {
+            return found;
+        }
+        dir = parent(dir);
+    }
+}
+
+fn python_dependencies(
+    path: &str,
+    contents: &str,
+    indexed: &HashSet<&str>,
+    patterns: &Patterns,
+) -> Vec<String> {
+    let mut dependencies = Vec::new();
+
+    for capture in patterns.python_from.captures_iter(contents) {
+        let (dots, module) = (capture[1].len(), &capture[2]);
+        let names: Vec<&str> = capture[3]
+            .trim_matches(|c: char| c == '(' || c == ')' || c.is_whitespace())
+            .split(',')
+            .filter_map(|name| name.split_whitespace().next())
+            .collect();
+
+        if dots > 0 {
+            // Relative to the package, each extra dot going up a level
+            let mut base = parent(path).to_string();
+            for _ in 1..dots {
+                base = parent(&base).to_string();
+            }
+            let module_path = join(&base, &module.replace('.', "/"));
+            // Imported names can be submodules
+            let mut candidates: Vec<String> = names
+                .iter()
+                .map(|name| join(&module_path, &format!("{}.py", name)))
+                .collect();
+            candidates.push(format!("{}.py", module_path));
+            candidates.push(format!("{}/__init__.py", module_path));
+            dependencies.extend(
+                candidates
+                    .into_iter()
+                    .filter(|c| indexed.contains(c.as_str())),
+            );
+        } else {
+            let submodules = names
+                .iter()
+                .filter_map(|name| {
+                    resolve_python_module(path, &format!("{}.{}", module, name), indexed)
+                })
+                .collect::<Vec<_>>();
+            if submodules.is_empty() {
+                dependencies.extend(resolve_python_module(path, module, indexed));
+            } else {
+                dependencies.extend(submodules);
+            }
+        }
+    }
+
+    for capture in patterns.python_import.captures_iter(contents) {
+        for module in capture[1].split(',') {
+            if let Some(module) = module.split_whitespace().next() {
+                dependencies.extend(resolve_python_module(path, module, indexed));
+            }
+        }
+    }
+
+    dependencies
+}
+
+fn go_dependencies(
+    contents: &str,
+    go_module: Option<&str>,
+    indexed: &HashSet<&str>,
+    patterns: &Patterns,
+) -> Vec<String> {
+    let Some(go_module) = go_module else {
+        return Vec::new();
+    };
+
+    let mut imports: Vec<String> = Vec::new();
+    for block in patterns.go_block.captures_iter(contents) {
+        imports.extend(
+            patterns
+                .go_path
+                .captures_iter(&block[1])
+                .map(|c| c[1].to_string()),
+        );
+    }
+    imports.extend(
+        patterns
+            .go_single
+            .captures_iter(contents)
+            .map(|c| c[1].to_string()),
+    );
+
+    // A package is every non-test file directly in its directory
+    let mut dependencies = Vec::new();
+    for import in imports {
+        let Some(package_dir) = import.strip_prefix(go_module) else {
+            continue;
+        };
+        let package_dir = package_dir.trim_start_matches('/');
+        dependencies.extend(
+            indexed
+                .iter()
+                .filter(|f| {
+                    f.ends_with(".go") && !f.ends_with("_test.go") && parent(f) == package_dir
+                })
+                .map(|f| f.to_string()),
+        );
+    }
+    dependencies
+}
+
+/// Module path declared in `go.mod`, if the scan includes one or it is in the
+/// current directory
+fn go_module(files: &[SourceFile]) -> Option<String> {
+    let go_mod = match files.iter().find(|f| f.path_str == "go.mod") {
+        Some(file) => file.contents.clone(),
+        None => std::fs::read_to_string("go.mod").ok()?,
+    };
+    go_mod.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix("module ")
+            .map(|m| m.trim().trim_matches('"').to_string())
+    })
+}
+
+/// Files each scanned file imports, limited to the scanned files. Covers Rust `mod`
+/// declarations and `crate::`/`super::`/`self::` paths, relative JS/TS imports,
+/// Python imports and Go imports within the module.
+pub fn dependency_graph(files: &[SourceFile]) -> HashMap<String, Vec<String>> {
+    let indexed: HashSet<&str> = files.iter().map(|f| f.path_str.as_str()).collect();
+    file_dependencies(files, &indexed)
+}
+
+/// Files each of `files` imports, limited to the `indexed` paths
+pub fn file_dependencies(
+    files: &[SourceFile],
+    indexed: &HashSet<&str>,
+) -> HashMap<String, Vec<String>> {
+    let patterns = Patterns::new();
+    let go_module = go_module(files);
+
+    let mut graph = HashMap::new();
+    for file in files {
+        let path = file.path_str.as_str();
+        let extension = path.rsplit_once('.').map_or("", |(_, e)| e);
+        let mut dependencies = match extension {
+            "rs" => rust_dependencies(path, &file.contents, indexed, &patterns),
+            e if JS_EXTENSIONS.contains(&e) => {
+                js_dependencies(path, &file.contents, indexed, &patterns)
+            }
+            "py" => python_dependencies(path, &file.contents, indexed, &patterns),
+            "go" => go_dependencies(&file.contents, go_module.as_deref(), indexed, &patterns),
+            _ => Vec::new(),
+        };
+        dependencies.retain(|d| d != path);
+        dependencies.sort();
+        dependencies.dedup();
+        if !dependencies.is_empty() {
+            graph.insert(path.to_string(), dependencies);
+        }
+    }
+    graph
+}
+
+/// Direct dependencies and dependents of `filename` in the stored graph, with their
+/// strength for expansion
+pub fn neighbors(store: &FileVectorStore, filename: &str) -> Vec<(String, f32, ExpansionKind)> {
+    let mut neighbors: Vec<(String, f32, ExpansionKind)> = store
+        .dependencies
+        .get(filename)
+        .into_iter()
+        .flatten()
+        .map(|d| (d.clone(), 1.0, ExpansionKind::Dependency))
+        .collect();
+    neighbors.extend(
+        store
+            .dependencies
+            .iter()
+            .filter(|(_, dependencies)| dependencies.iter().any(|d| d == filename))
+            .map(|(dependent, _)| {
+                (
+                    dependent.clone(),
+                    DEPENDENT_STRENGTH,
+                    ExpansionKind::Dependent,
+                )
+            }),
+    );
+    neighbors
+}
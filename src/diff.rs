@@ -0,0 +1,161 @@
+use colored::Colorize;
+
+// Unchanged lines shown around each change
+const CONTEXT_LINES: usize = 3;
+
+// Above this many line pairs, a changed region is shown as replaced wholesale
+const MAX_LCS_CELLS: usize = 25_000_000;
+
+#[derive(Clone, Copy)]
+enum Op {
+    Equal(usize), // Index of the line in the old version
+    Delete(usize),
+    Insert(usize),
+}
+
+// Line operations turning `old` into `new`. The common prefix and suffix are matched
+// directly, the rest with a longest common subsequence.
+fn line_ops(old: &[&str], new: &[&str]) -> Vec<Op> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_middle, new_middle) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+    let (n, m) = (old_middle.len(), new_middle.len());
+
+    let mut ops: Vec<Op> = (0..prefix).map(Op::Equal).collect();
+    if n * m <= MAX_LCS_CELLS {
+        // lengths[i][j]: LCS length of old_middle[i..] and new_middle[j..]
+        let mut lengths = vec![vec![0u32; m + 1]; n + 1];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lengths[i][j] = if old_middle[i] == new_middle[j] {
+                    lengths[i + 1][j + 1] + 1
+                } else {
+                    lengths[i + 1][j].max(lengths[i][j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && old_middle[i] == new_middle[j] {
+                ops.push(Op::Equal(prefix + i));
+                i += 1;
+                j += 1;
+            } else if i < n && (j == m || lengths[i + 1][j] >= lengths[i][j + 1]) {
+                // Removed lines come before added ones
+                ops.push(Op::Delete(prefix + i));
+                i += 1;
+            } else {
+                ops.push(Op::Insert(prefix + j));
+                j += 1;
+            }
+        }
+    } else {
+        ops.extend((0..n).map(|i| Op::Delete(prefix + i)));
+        ops.extend((0..m).map(|j| Op::Insert(prefix + j)));
+    }
+    ops.extend((old.len() - suffix..old.len()).map(Op::Equal));
+    ops
+}
+
+fn hunk_range(start: usize, count: usize) -> String {
+    // An empty range names the line before it
+    let start = if count == 0 { start } else { start + 1 };
+    format!("{},{}", start, count)
+}
+
+/// Unified diff of two versions of a file, empty when they are the same
+pub fn unified_diff(filename: &str, old: &str, new: &str) -> String {
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let ops = line_ops(&old_lines, &new_lines);
+    let changes: Vec<usize> = (0..ops.len())
+        .filter(|&k| !matches!(ops[k], Op::Equal(_)))
+        .collect();
+    if changes.is_empty() {
+        return String::new();
+    }
+
+    // Lines of each version before each operation
+    let mut positions = Vec::with_capacity(ops.len());
+    let (mut old_position, mut new_position) = (0, 0);
+    for op in &ops {
+        positions.push((old_position, new_position));
+        match op {
+            Op::Equal(_) => {
+                old_position += 1;
+                new_position += 1;
+            }
+            Op::Delete(_) => old_position += 1,
+            Op::Insert(_) => new_position += 1,
+        }
+    }
+
+    let mut diff = format!("--- a/{}\n+++ b/{}\n", filename, filename);
+    let mut next = 0;
+    while next < changes.len() {
+        // Changes closer than twice the context share a hunk
+        let mut last = next;
+        while last + 1 < changes.len() && changes[last + 1] - changes[last] <= 2 * CONTEXT_LINES {
+            last += 1;
+        }
+        let start = changes[next].saturating_sub(CONTEXT_LINES);
+        let end = (changes[last] + CONTEXT_LINES + 1).min(ops.len());
+        let old_count = ops[start..end]
+            .iter()
+            .filter(|op| !matches!(op, Op::Insert(_)))
+            .count();
+        let new_count = ops[start..end]
+            .iter()
+            .filter(|op| !matches!(op, Op::Delete(_)))
+            .count();
+        diff.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(positions[start].0, old_count),
+            hunk_range(positions[start].1, new_count)
+        ));
+        for op in &ops[start..end] {
+            let (marker, line) = match *op {
+                Op::Equal(i) => (' ', old_lines[i]),
+                Op::Delete(i) => ('-', old_lines[i]),
+                Op::Insert(j) => ('+', new_lines[j]),
+            };
+            diff.push(marker);
+            diff.push_str(line);
+            if !line.ends_with('\n') {
+                diff.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+        next = last + 1;
+    }
+    diff
+}
+
+/// Print a unified diff with removed lines red, added lines green and hunk headers cyan
+pub fn print_colored(diff: &str) {
+    // File headers only come before the first hunk, removed lines may look like them
+    let mut in_header = true;
+    for line in diff.lines() {
+        if line.starts_with("@@") {
+            in_header = false;
+        }
+        if in_header && (line.starts_with("---") || line.starts_with("+++")) {
+            println!("{}", line.bold());
+        } else if line.starts_with("@@") {
+            println!("{}", line.cyan());
+        } else if line.starts_with('-') {
+            println!("{}", line.red());
+        } else if line.starts_with('+') {
+            println!("{}", line.green());
+        } else {
+            println!("{}", line);
+        }
+    }
+}
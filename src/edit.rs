@@ -0,0 +1,305 @@
+use crate::cache::EmbeddingCache;
+use crate::openai::ChatUsage;
+use crate::pack::PackFormat;
+use crate::scan::FileVectorStore;
+use crate::search::{SearchOptions, WeightedQuery};
+use crate::token_count::count_tokens;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+const EDIT_SYSTEM_PROMPT: &str = "You change code in a repository to carry out a task. You \
+are given the full contents of the files you may change. Reply with search/replace edits: \
+`search` is an exact excerpt of the file's current contents, whitespace included, long \
+enough to occur only once in the file, and `replace` is the text that takes its place. \
+Edits to the same file apply in order. Only edit the files given, keep edits minimal and \
+match the surrounding code's style. Briefly explain the change.";
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Edit {
+    path: String,
+    search: String,
+    replace: String,
+}
+
+#[derive(Deserialize)]
+struct EditResponse {
+    explanation: String,
+    edits: Vec<Edit>,
+}
+
+pub struct EditOptions {
+    pub files: usize,      // Number of best files the model may change
+    pub max_tokens: usize, // Budget for their contents
+    pub retries: usize,    // Times failed edits are sent back to the model
+    pub yes: bool,         // Apply without asking
+    pub partial: bool,     // With yes, apply the edits that worked even if others failed
+}
+
+/// An edit that couldn't be applied, and why
+struct FailedEdit {
+    number: usize, // 1-based, in the model's order
+    path: String,
+    reason: String,
+}
+
+fn edits_schema() -> serde_json::Value {
+    serde_json::json!({
+        "type": "object",
+        "properties": {
+            "explanation": { "type": "string" },
+            "edits": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string" },
+                        "search": { "type": "string" },
+                        "replace": { "type": "string" }
+                    },
+                    "required": ["path", "search", "replace"],
+                    "additionalProperties": false
+                }
+            }
+        },
+        "required": ["explanation", "edits"],
+        "additionalProperties": false
+    })
+}
+
+/// Contents of the best files for the task, in score order, while they fit in
+/// `max_tokens`. Files that don't fit are reported and left out.
+async fn retrieve_files(
+    store: &FileVectorStore,
+    task: &str,
+    api_key: &str,
+    options: &SearchOptions,
+    file_count: usize,
+    max_tokens: usize,
+    embeddings: &EmbeddingCache,
+) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+    let queries = [WeightedQuery::new(task)];
+    let matches = crate::search::rank_files(store, &queries, api_key, options, embeddings).await?;
+    let mut filenames: Vec<&str> = Vec::new();
+    for m in &matches {
+        if !filenames.contains(&m.filename.as_str()) {
+            filenames.push(&m.filename);
+        }
+    }
+
+    let mut files = BTreeMap::new();
+    let mut used = 0;
+    for filename in filenames.into_iter().take(file_count) {
+        let Ok(contents) = std::fs::read_to_string(filename) else {
+            continue;
+        };
+        let tokens = count_tokens(&contents);
+        if used + tokens > max_tokens {
+            eprintln!(
+                "Leaving out {} ({} tokens), it doesn't fit in {} tokens",
+                filename, tokens, max_tokens
+            );
+            continue;
+        }
+        used += tokens;
+        files.insert(filename.to_string(), contents);
+    }
+    Ok(files)
+}
+
+fn render_files(files: &BTreeMap<String, String>) -> String {
+    files
+        .iter()
+        .map(|(filename, contents)| {
+            let lines = contents.split_inclusive('\n').count();
+            crate::pack::render_range(PackFormat::Markdown, filename, 0, lines, contents)
+        })
+        .collect()
+}
+
+/// Apply the edits to copies of the files, in order. Edits to other files, and edits
+/// whose search text isn't found exactly once, are skipped and returned as failures.
+fn apply_edits(
+    files: &BTreeMap<String, String>,
+    edits: &[Edit],
+) -> (BTreeMap<String, String>, Vec<FailedEdit>) {
+    let mut edited = files.clone();
+    let mut failed = Vec::new();
+    for (i, edit) in edits.iter().enumerate() {
+        let path = crate::update::normalize_path(&edit.path);
+        let fail = |reason: String| FailedEdit {
+            number: i + 1,
+            path: path.clone(),
+            reason,
+        };
+        let Some(contents) = edited.get_mut(&path) else {
+            failed.push(fail("not one of the files given".to_string()));
+            continue;
+        };
+        if edit.search.is_empty() {
+            failed.push(fail("the search text is empty".to_string()));
+            continue;
+        }
+        match contents.matches(edit.search.as_str()).count() {
+            0 => failed.push(fail("the search text isn't in the file".to_string())),
+            1 => *contents = contents.replacen(&edit.search, &edit.replace, 1),
+            n => failed.push(fail(format!(
+                "the search text occurs {} times, it must be unique",
+                n
+            ))),
+        }
+    }
+    (edited, failed)
+}
+
+fn confirm(question: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Ask the chat model for search/replace edits carrying out the task in the best files
+/// for it, show them as a diff and write them after confirmation. Edits that fail to
+/// apply are sent back to the model up to `options.retries` times.
+pub async fn edit(
+    store: &FileVectorStore,
+    task: &str,
+    api_key: &str,
+    search_options: &SearchOptions,
+    options: &EditOptions,
+    embeddings: &EmbeddingCache,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Fail before paying for edits that couldn't be confirmed
+    if !options.yes && atty::isnt(atty::Stream::Stdin) {
+        return Err("Can't ask for confirmation without a terminal, pass --yes to apply".into());
+    }
+    let files = retrieve_files(
+        store,
+        task,
+        api_key,
+        search_options,
+        options.files,
+        options.max_tokens,
+        embeddings,
+    )
+    .await?;
+    if files.is_empty() {
+        return Err("No relevant files found for the task".into());
+    }
+    eprintln!(
+        "Editing with {}",
+        files.keys().cloned().collect::<Vec<_>>().join(", ")
+    );
+
+    let base_prompt = format!("{}# Task\n\n{}", render_files(&files), task);
+    let mut prompt = base_prompt.clone();
+    let mut usage = ChatUsage::default();
+    let mut attempt = 0;
+    let (response, edited, failed) = loop {
+        let (content, response_usage) = crate::openai::get_openai_json_completion(
+            &prompt,
+            EDIT_SYSTEM_PROMPT,
+            "edits",
+            edits_schema(),
+            api_key,
+        )
+        .await?;
+        usage.prompt_tokens += response_usage.prompt_tokens;
+        usage.completion_tokens += response_usage.completion_tokens;
+        let response: EditResponse = serde_json::from_str(&content)?;
+        let (edited, failed) = apply_edits(&files, &response.edits);
+        if failed.is_empty() || attempt == options.retries {
+            break (response, edited, failed);
+        }
+
+        attempt += 1;
+        eprintln!(
+            "{} of {} edit(s) failed to apply, retrying ({}/{})",
+            failed.len(),
+            response.edits.len(),
+            attempt,
+            options.retries
+        );
+        let failures: Vec<String> = failed
+            .iter()
+            .map(|f| format!("- Edit {} to {}: {}", f.number, f.path, f.reason))
+            .collect();
+        prompt = format!(
+            "{}\n\n# Previous attempt\n\n{}\n\nThese edits failed to apply:\n{}\n\nReply with \
+             the complete, corrected set of edits, with search texts copied exactly from the \
+             files above.",
+            base_prompt,
+            serde_json::to_string_pretty(&response.edits)?,
+            failures.join("\n")
+        );
+    };
+
+    let config = crate::config::load_config();
+    let cost = crate::estimate::chat_cost(crate::openai::CHAT_MODEL, &usage, &config)
+        .map_or("unknown".to_string(), |cost| format!("${:.4}", cost));
+    eprintln!(
+        "Edit: {} prompt + {} completion tokens, cost {}",
+        usage.prompt_tokens, usage.completion_tokens, cost
+    );
+
+    println!("{}\n", response.explanation.trim());
+    let changed: Vec<(&String, &String)> = edited
+        .iter()
+        .filter(|(filename, contents)| files.get(*filename) != Some(*contents))
+        .collect();
+    for (filename, contents) in &changed {
+        crate::diff::print_colored(&crate::diff::unified_diff(
+            filename,
+            &files[*filename],
+            contents,
+        ));
+    }
+    if !failed.is_empty() {
+        eprintln!(
+            "\n{} of {} edit(s) failed to apply:",
+            failed.len(),
+            response.edits.len()
+        );
+        for f in &failed {
+            eprintln!("  Edit {} to {}: {}", f.number, f.path, f.reason);
+        }
+    }
+    if changed.is_empty() {
+        eprintln!("No changes to apply");
+        return Ok(());
+    }
+
+    // Part of a change can leave the code broken, so it's only applied when asked for
+    if !failed.is_empty() && options.yes && !options.partial {
+        return Err(format!(
+            "Not applied: {} edit(s) failed, pass --partial to apply the rest",
+            failed.len()
+        )
+        .into());
+    }
+    let question = if failed.is_empty() {
+        format!("\nApply the changes to {} file(s)?", changed.len())
+    } else {
+        format!(
+            "\nApply the changes to {} file(s) without the {} failed edit(s)?",
+            changed.len(),
+            failed.len()
+        )
+    };
+    if !options.yes && !confirm(&question)? {
+        eprintln!("Not applied");
+        return Ok(());
+    }
+    for (filename, contents) in &changed {
+        std::fs::write(filename, contents)?;
+    }
+    let filenames: Vec<&str> = changed.iter().map(|(f, _)| f.as_str()).collect();
+    println!(
+        "Applied to {}. Update the index with: luckyshot add {}",
+        filenames.join(", "),
+        filenames.join(" ")
+    );
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use crate::expand::Expansion;
+use crate::search::FileMatch;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fs;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum EmitFormat {
+    /// Script of `/add` and `/read-only` commands for aider's `/load`
+    Aider,
+    /// Markdown list of `@path` references
+    Markdown,
+    /// JSON file manifest
+    Json,
+}
+
+#[derive(Serialize)]
+struct ManifestFile<'a> {
+    path: &'a str,
+    score: f32,
+    mode: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expansion: Option<&'a Expansion>,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    files: Vec<ManifestFile<'a>>,
+}
+
+/// Render the matches for a coding agent. Files scoring at least `edit_threshold` are
+/// editable, the rest are read-only context.
+pub fn render(format: EmitFormat, matches: &[FileMatch], edit_threshold: f32) -> String {
+    let is_editable = |m: &FileMatch| m.similarity >= edit_threshold;
+
+    match format {
+        EmitFormat::Aider => matches
+            .iter()
+            .map(|m| {
+                let command = if is_editable(m) { "/add" } else { "/read-only" };
+                format!("{} {}\n", command, m.filename)
+            })
+            .collect(),
+        EmitFormat::Markdown => matches
+            .iter()
+            .map(|m| {
+                let mut notes = Vec::new();
+                if !is_editable(m) {
+                    notes.push("read-only".to_string());
+                }
+                if let Some(expansion) = &m.expansion {
+                    notes.push(expansion.to_string());
+                }
+                if notes.is_empty() {
+                    format!("- @{}\n", m.filename)
+                } else {
+                    format!("- @{} ({})\n", m.filename, notes.join(", "))
+                }
+            })
+            .collect(),
+        EmitFormat::Json => {
+            let manifest = Manifest {
+                files: matches
+                    .iter()
+                    .map(|m| ManifestFile {
+                        path: &m.filename,
+                        score: m.similarity,
+                        mode: if is_editable(m) { "edit" } else { "read-only" },
+                        expansion: m.expansion.as_ref(),
+                    })
+                    .collect(),
+            };
+            // Serializing plain strings and floats can't fail
+            serde_json::to_string_pretty(&manifest).unwrap() + "\n"
+        }
+    }
+}
+
+/// Write the rendered matches to `output`, or stdout if there is none
+pub fn emit_files(
+    format: EmitFormat,
+    matches: &[FileMatch],
+    edit_threshold: f32,
+    output: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let rendered = render(format, matches, edit_threshold);
+    match output {
+        Some(path) => {
+            fs::write(path, rendered).map_err(|e| format!("Error writing {}: {}", path, e))?;
+            eprintln!("Wrote {} files to {}", matches.len(), path);
+        }
+        None => print!("{}", rendered),
+    }
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use crate::config::{ChatPrice, Config};
+use crate::openai::ChatUsage;
+use crate::scan::{
+    collect_source_files, create_chunks, is_unchanged, load_reusable_store, ScanSource,
+};
+use crate::token_count::count_tokens;
+
+// USD per million tokens, overridable with `embedding_prices` in the config file
+const DEFAULT_EMBEDDING_PRICES: &[(&str, f64)] = &[
+    ("text-embedding-ada-002", 0.10),
+    ("text-embedding-3-small", 0.02),
+    ("text-embedding-3-large", 0.13),
+];
+
+// USD per million input and output tokens, overridable with `chat_prices` in the config file
+const DEFAULT_CHAT_PRICES: &[(&str, ChatPrice)] = &[
+    (
+        "gpt-4o-mini",
+        ChatPrice {
+            input: 0.15,
+            output: 0.60,
+        },
+    ),
+    (
+        "gpt-4o",
+        ChatPrice {
+            input: 2.50,
+            output: 10.00,
+        },
+    ),
+];
+
+pub struct FileEstimate {
+    pub filename: String,
+    pub chunks: usize,
+    pub tokens: usize,
+}
+
+pub struct ScanEstimate {
+    pub files: Vec<FileEstimate>,
+    pub reused_files: usize, // Unchanged since the last scan, their embeddings are kept
+    pub total_chunks: usize,
+    pub total_tokens: usize,
+    pub model: String,
+    pub price_per_million: Option<f64>,
+}
+
+impl ScanEstimate {
+    pub fn cost(&self) -> Option<f64> {
+        self.price_per_million
+            .map(|price| self.total_tokens as f64 / 1_000_000.0 * price)
+    }
+}
+
+pub fn embedding_price(model: &str, config: &Config) -> Option<f64> {
+    config.embedding_prices.get(model).copied().or_else(|| {
+        DEFAULT_EMBEDDING_PRICES
+            .iter()
+            .find(|(name, _)| *name == model)
+            .map(|(_, price)| *price)
+    })
+}
+
+pub fn chat_price(model: &str, config: &Config) -> Option<ChatPrice> {
+    config.chat_prices.get(model).copied().or_else(|| {
+        DEFAULT_CHAT_PRICES
+            .iter()
+            .find(|(name, _)| *name == model)
+            .map(|(_, price)| *price)
+    })
+}
+
+/// Cost in USD of chat completions with the given usage, if the model's price is known
+pub fn chat_cost(model: &str, usage: &ChatUsage, config: &Config) -> Option<f64> {
+    chat_price(model, config).map(|price| {
+        (usage.prompt_tokens as f64 * price.input + usage.completion_tokens as f64 * price.output)
+            / 1_000_000.0
+    })
+}
+
+/// Count the tokens a scan would send for embedding, without calling the API. Files
+/// whose embeddings the scan would reuse from the last one are only counted as reused.
+pub fn estimate_scan(
+    pattern: &str,
+    chunk_size: usize,
+    overlap_size: usize,
+    embed_metadata: bool,
+    source: &ScanSource,
+    config: &Config,
+) -> Result<ScanEstimate, Box<dyn std::error::Error>> {
+    let previous = load_reusable_store(chunk_size, overlap_size, embed_metadata);
+    let mut files = Vec::new();
+    let mut reused_files = 0;
+
+    for file in collect_source_files(pattern, source)? {
+        if previous
+            .as_ref()
+            .is_some_and(|previous| is_unchanged(previous, &file))
+        {
+            reused_files += 1;
+            continue;
+        }
+        let chunks = create_chunks(&file.contents, chunk_size, overlap_size);
+        let tokens = chunks
+            .iter()
+            .map(|(_, chunk_content)| {
+                if embed_metadata {
+                    count_tokens(&crate::metadata::prepend_metadata(
+                        &file.path_str,
+                        file.last_modified,
+                        file.size,
+                        chunk_content,
+                    ))
+                } else {
+                    count_tokens(chunk_content)
+                }
+            })
+            .sum();
+
+        files.push(FileEstimate {
+            filename: file.path_str,
+            chunks: chunks.len(),
+            tokens,
+        });
+    }
+
+    // Largest contributors first
+    files.sort_by_key(|f| std::cmp::Reverse(f.tokens));
+
+    let model = crate::openai::EMBEDDING_MODEL.to_string();
+    Ok(ScanEstimate {
+        total_chunks: files.iter().map(|f| f.chunks).sum(),
+        total_tokens: files.iter().map(|f| f.tokens).sum(),
+        price_per_million: embedding_price(&model, config),
+        model,
+        files,
+        reused_files,
+    })
+}
+
+pub fn print_estimate(estimate: &ScanEstimate, top: usize) {
+    println!("Files: {}", estimate.files.len());
+    if estimate.reused_files > 0 {
+        println!(
+            "Reused: {} unchanged files, already embedded",
+            estimate.reused_files
+        );
+    }
+    println!("Chunks: {}", estimate.total_chunks);
+    println!("Tokens: {}", estimate.total_tokens);
+
+    if !estimate.files.is_empty() {
+        println!("\nLargest contributors:");
+        println!("Tokens,Chunks,File");
+        for f in estimate.files.iter().take(top) {
+            println!("{},{},{}", f.tokens, f.chunks, f.filename);
+        }
+        println!();
+    }
+
+    match (estimate.price_per_million, estimate.cost()) {
+        (Some(price), Some(cost)) => println!(
+            "Estimated cost: ${:.4} ({} at ${} per 1M tokens)",
+            cost, estimate.model, price
+        ),
+        _ => println!(
+            "Estimated cost: unknown (no price for {}, add it to embedding_prices in {})",
+            estimate.model,
+            crate::config::CONFIG_FILE
+        ),
+    }
+}
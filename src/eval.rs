@@ -0,0 +1,312 @@
+use crate::cache::EmbeddingCache;
+use crate::scan::FileVectorStore;
+use crate::search::{FileMatch, SearchOptions, WeightedQuery};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+// Width of the query column in reports
+const QUERY_WIDTH: usize = 40;
+
+/// One line of an eval dataset: a query and the files or `path:start-end` spans that
+/// should be found for it
+#[derive(Serialize, Deserialize)]
+pub struct EvalQuery {
+    pub query: String,
+    pub expected: Vec<String>,
+}
+
+struct Expected {
+    path: String,
+    lines: Option<(usize, usize)>,
+}
+
+fn parse_expected(item: &str) -> Expected {
+    let span = item.rsplit_once(':').and_then(|(path, range)| {
+        let (start, end) = range.split_once('-')?;
+        Some((path, (start.parse().ok()?, end.parse().ok()?)))
+    });
+    match span {
+        Some((path, lines)) => Expected {
+            path: path.to_string(),
+            lines: Some(lines),
+        },
+        None => Expected {
+            path: item.to_string(),
+            lines: None,
+        },
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Metric {
+    Recall,
+    Precision,
+    Mrr,
+    Ndcg,
+}
+
+impl Metric {
+    pub fn of(&self, metrics: &Metrics) -> f32 {
+        match self {
+            Metric::Recall => metrics.recall,
+            Metric::Precision => metrics.precision,
+            Metric::Mrr => metrics.mrr,
+            Metric::Ndcg => metrics.ndcg,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Metrics {
+    pub recall: f32,
+    pub precision: f32,
+    pub mrr: f32,
+    pub ndcg: f32,
+}
+
+impl Metrics {
+    fn values(&self) -> [f32; 4] {
+        [self.recall, self.precision, self.mrr, self.ndcg]
+    }
+
+    pub fn mean(metrics: &[Metrics]) -> Metrics {
+        let n = metrics.len().max(1) as f32;
+        Metrics {
+            recall: metrics.iter().map(|m| m.recall).sum::<f32>() / n,
+            precision: metrics.iter().map(|m| m.precision).sum::<f32>() / n,
+            mrr: metrics.iter().map(|m| m.mrr).sum::<f32>() / n,
+            ndcg: metrics.iter().map(|m| m.ndcg).sum::<f32>() / n,
+        }
+    }
+}
+
+pub fn load_dataset(path: &str) -> Result<Vec<EvalQuery>, Box<dyn std::error::Error>> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Error reading {}: {}", path, e))?;
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| format!("{} line {}: {}", path, i + 1, e).into())
+        })
+        .collect()
+}
+
+// A result is relevant to an expected file, or to an expected span its chunk overlaps
+fn is_relevant(store: &FileVectorStore, m: &FileMatch, expected: &Expected) -> bool {
+    if m.filename != expected.path {
+        return false;
+    }
+    let Some((start, end)) = expected.lines else {
+        return true;
+    };
+    let span = store.rag_vectors[m.chunk_index].span;
+    !span.is_known() || (span.start_line <= end && start <= span.end_line)
+}
+
+/// Recall@k, precision@k, MRR and nDCG@k (binary gains) of the ranked results
+fn score_results(
+    store: &FileVectorStore,
+    results: &[FileMatch],
+    expected: &[Expected],
+    k: usize,
+) -> Metrics {
+    let top = &results[..results.len().min(k)];
+    let relevant: Vec<bool> = top
+        .iter()
+        .map(|m| expected.iter().any(|e| is_relevant(store, m, e)))
+        .collect();
+
+    let found = expected
+        .iter()
+        .filter(|e| top.iter().any(|m| is_relevant(store, m, e)))
+        .count();
+    let dcg: f32 = relevant
+        .iter()
+        .enumerate()
+        .filter(|(_, &r)| r)
+        .fold(0.0, |dcg, (i, _)| dcg + 1.0 / (i as f32 + 2.0).log2());
+    let ideal_dcg: f32 =
+        (0..expected.len().min(k)).fold(0.0, |dcg, i| dcg + 1.0 / (i as f32 + 2.0).log2());
+
+    Metrics {
+        recall: found as f32 / expected.len().max(1) as f32,
+        precision: relevant.iter().filter(|&&r| r).count() as f32 / k as f32,
+        mrr: relevant
+            .iter()
+            .position(|&r| r)
+            .map_or(0.0, |i| 1.0 / (i as f32 + 1.0)),
+        ndcg: if ideal_dcg > 0.0 {
+            dcg / ideal_dcg
+        } else {
+            0.0
+        },
+    }
+}
+
+/// Run every query through the search pipeline and score its top `k` files
+pub async fn evaluate(
+    store: &FileVectorStore,
+    dataset: &[EvalQuery],
+    api_key: &str,
+    options: &SearchOptions,
+    k: usize,
+    embeddings: &EmbeddingCache,
+) -> Result<Vec<Metrics>, Box<dyn std::error::Error>> {
+    let mut metrics = Vec::new();
+    for item in dataset {
+        let queries = [WeightedQuery::new(&item.query)];
+        let results =
+            crate::search::rank_files(store, &queries, api_key, options, embeddings).await?;
+        let expected: Vec<Expected> = item.expected.iter().map(|e| parse_expected(e)).collect();
+        metrics.push(score_results(store, &results, &expected, k));
+    }
+    Ok(metrics)
+}
+
+fn truncate_query(query: &str) -> String {
+    let query = query.replace('\n', " ");
+    if query.chars().count() > QUERY_WIDTH {
+        let truncated: String = query.chars().take(QUERY_WIDTH - 3).collect();
+        format!("{}...", truncated)
+    } else {
+        query
+    }
+}
+
+fn header(k: usize) -> [String; 4] {
+    [
+        format!("R@{}", k),
+        format!("P@{}", k),
+        "MRR".to_string(),
+        format!("nDCG@{}", k),
+    ]
+}
+
+/// Per-query and mean metrics of one configuration
+pub fn print_report(dataset: &[EvalQuery], metrics: &[Metrics], k: usize) {
+    let [recall, precision, mrr, ndcg] = header(k);
+    println!(
+        "{:<width$}  {:>7} {:>7} {:>7} {:>7}",
+        "Query",
+        recall,
+        precision,
+        mrr,
+        ndcg,
+        width = QUERY_WIDTH
+    );
+    for (item, m) in dataset.iter().zip(metrics) {
+        println!(
+            "{:<width$}  {:>7.3} {:>7.3} {:>7.3} {:>7.3}",
+            truncate_query(&item.query),
+            m.recall,
+            m.precision,
+            m.mrr,
+            m.ndcg,
+            width = QUERY_WIDTH
+        );
+    }
+    let mean = Metrics::mean(metrics);
+    println!(
+        "{:<width$}  {:>7.3} {:>7.3} {:>7.3} {:>7.3}",
+        format!("Mean of {} queries", metrics.len()),
+        mean.recall,
+        mean.precision,
+        mean.mrr,
+        mean.ndcg,
+        width = QUERY_WIDTH
+    );
+}
+
+/// Metrics of a baseline (A) and a candidate (B) configuration side by side. Queries
+/// where B is worse on any metric are marked with `!`.
+pub fn print_comparison(dataset: &[EvalQuery], a: &[Metrics], b: &[Metrics], k: usize) {
+    let cell = |a: f32, b: f32| format!("{:.3}/{:.3}", a, b);
+    let row = |label: String, a: &Metrics, b: &Metrics, marker: &str| {
+        let cells: Vec<String> = a
+            .values()
+            .iter()
+            .zip(b.values())
+            .map(|(a, b)| format!("{:>13}", cell(*a, b)))
+            .collect();
+        println!(
+            "{:<width$} {}{}",
+            label,
+            marker,
+            cells.join(" "),
+            width = QUERY_WIDTH
+        );
+    };
+
+    let titles: Vec<String> = header(k)
+        .iter()
+        .map(|t| format!("{:>13}", format!("{} A/B", t)))
+        .collect();
+    println!(
+        "{:<width$}  {}",
+        "Query",
+        titles.join(" "),
+        width = QUERY_WIDTH
+    );
+
+    let mut regressions = 0;
+    for ((item, a), b) in dataset.iter().zip(a).zip(b) {
+        let regressed = a
+            .values()
+            .iter()
+            .zip(b.values())
+            .any(|(a, b)| b < *a - 1e-6);
+        if regressed {
+            regressions += 1;
+        }
+        row(
+            truncate_query(&item.query),
+            a,
+            b,
+            if regressed { "!" } else { " " },
+        );
+    }
+
+    let (mean_a, mean_b) = (Metrics::mean(a), Metrics::mean(b));
+    row(
+        format!("Mean of {} queries", a.len()),
+        &mean_a,
+        &mean_b,
+        " ",
+    );
+    let deltas: Vec<String> = mean_a
+        .values()
+        .iter()
+        .zip(mean_b.values())
+        .map(|(a, b)| format!("{:>+13.3}", b - a))
+        .collect();
+    println!(
+        "{:<width$}  {}",
+        "Change (B - A)",
+        deltas.join(" "),
+        width = QUERY_WIDTH
+    );
+    println!("{} of {} queries regressed", regressions, a.len());
+}
+
+/// Split a command line into arguments, honoring single and double quotes
+pub fn split_args(line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quote: Option<char> = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                current.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(current.take()),
+            (None, c) => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(current);
+    args
+}
@@ -0,0 +1,93 @@
+use crate::scan::FileVectorStore;
+use crate::search::{FileMatch, ScoreComponents};
+use serde::Serialize;
+use std::collections::HashMap;
+
+// Number of distinct top files whose neighbors are considered
+const EXPANSION_SEEDS: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExpansionKind {
+    CoChange,
+    Dependency, // Imported by the hit
+    Dependent,  // Imports the hit
+}
+
+/// Why a file was added to the results without matching the query itself
+#[derive(Clone, Debug, Serialize)]
+pub struct Expansion {
+    pub kind: ExpansionKind,
+    pub from: String, // The hit whose neighbor this is
+}
+
+impl std::fmt::Display for Expansion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.kind {
+            ExpansionKind::CoChange => write!(f, "co-changes with {}", self.from),
+            ExpansionKind::Dependency => write!(f, "imported by {}", self.from),
+            ExpansionKind::Dependent => write!(f, "imports {}", self.from),
+        }
+    }
+}
+
+/// Append up to `limit` neighbors of the best hits that aren't results already. A
+/// neighbor scores its hit's score times its strength (0-1) times `weight`. Only
+/// indexed files are added, using their first chunk.
+pub fn expand(
+    store: &FileVectorStore,
+    matches: &mut Vec<FileMatch>,
+    limit: usize,
+    weight: f32,
+    neighbors: impl Fn(&str) -> Vec<(String, f32, ExpansionKind)>,
+) {
+    let mut seeds: Vec<&FileMatch> = Vec::new();
+    for m in matches.iter().filter(|m| m.expansion.is_none()) {
+        if seeds.len() < EXPANSION_SEEDS && !seeds.iter().any(|s| s.filename == m.filename) {
+            seeds.push(m);
+        }
+    }
+
+    let mut candidates: HashMap<String, (f32, ExpansionKind, &str)> = HashMap::new();
+    for seed in &seeds {
+        for (neighbor, strength, kind) in neighbors(&seed.filename) {
+            let score = seed.similarity * strength * weight;
+            let best = candidates
+                .entry(neighbor)
+                .or_insert((score, kind, &seed.filename));
+            if score > best.0 {
+                *best = (score, kind, &seed.filename);
+            }
+        }
+    }
+
+    let mut expansions: Vec<FileMatch> = candidates
+        .into_iter()
+        .filter(|(filename, _)| !matches.iter().any(|m| &m.filename == filename))
+        .filter_map(|(filename, (score, kind, from))| {
+            let chunk_index = store
+                .rag_vectors
+                .iter()
+                .position(|c| c.filename == filename)?;
+            Some(FileMatch {
+                filename,
+                similarity: score,
+                chunk_index,
+                expansion: Some(Expansion {
+                    kind,
+                    from: from.to_string(),
+                }),
+                components: ScoreComponents::default(),
+            })
+        })
+        .collect();
+    expansions.sort_by(|a, b| {
+        b.similarity
+            .partial_cmp(&a.similarity)
+            .unwrap()
+            .then_with(|| a.filename.cmp(&b.filename))
+    });
+    expansions.truncate(limit);
+
+    matches.extend(expansions);
+}
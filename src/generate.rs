@@ -0,0 +1,222 @@
+use crate::cache::EmbeddingCache;
+use crate::eval::EvalQuery;
+use crate::openai::ChatUsage;
+use crate::random::Random;
+use crate::scan::FileVectorStore;
+use crate::similarity::dot_product_similarity;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+// Chunks shorter than this rarely answer a question on their own
+const MIN_CHUNK_CHARS: usize = 200;
+
+// Keep prompts small, like the reranker does
+const MAX_SNIPPET_CHARS: usize = 3000;
+
+// Give up when the model keeps repeating itself rather than pay for every chunk
+const MAX_FRUITLESS_CHUNKS: usize = 20;
+
+const GENERATE_SYSTEM_PROMPT: &str = "You write the questions developers type into a code \
+search tool. Given a snippet from a codebase, write questions that this snippet answers. \
+Phrase them the way someone who has not read the code would: describe behavior and intent, \
+and avoid copying identifiers from the snippet unless a developer would know them. Each \
+question is a single sentence and asks something different.";
+
+/// What sampled chunks are spread evenly across
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Stratum {
+    /// The file's directory
+    Dir,
+    /// The file's extension
+    Ext,
+}
+
+pub struct GenerateOptions {
+    pub count: usize,         // Queries to collect
+    pub per_chunk: usize,     // Questions asked about each sampled chunk
+    pub strata: Vec<Stratum>, // What sampled chunks are spread across
+    pub max_similarity: f32,  // Questions at least this similar to an earlier one are skipped
+    pub seed: u64,            // Seed of the chunk sampling
+}
+
+#[derive(Deserialize)]
+struct Questions {
+    questions: Vec<String>,
+}
+
+fn questions_schema() -> serde_json::Value {
+    serde_json::json!({
+        "type": "object",
+        "properties": {
+            "questions": {
+                "type": "array",
+                "items": { "type": "string" }
+            }
+        },
+        "required": ["questions"],
+        "additionalProperties": false
+    })
+}
+
+fn stratum_key(filename: &str, strata: &[Stratum]) -> String {
+    strata
+        .iter()
+        .map(|stratum| match stratum {
+            Stratum::Dir => filename.rfind('/').map_or("", |i| &filename[..i]),
+            Stratum::Ext => filename.rsplit_once('.').map_or("", |(_, e)| e),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Every chunk long enough to ask about, in random order. With strata, the order
+/// takes one chunk from each stratum in turn, so any prefix covers them evenly.
+fn sample_chunks(store: &FileVectorStore, strata: &[Stratum], random: &mut Random) -> Vec<usize> {
+    let mut groups: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (i, chunk) in store.rag_vectors.iter().enumerate() {
+        if chunk.chunk_size >= MIN_CHUNK_CHARS {
+            groups
+                .entry(stratum_key(&chunk.filename, strata))
+                .or_default()
+                .push(i);
+        }
+    }
+
+    let mut groups: Vec<Vec<usize>> = groups.into_values().collect();
+    random.shuffle(&mut groups);
+    for group in &mut groups {
+        random.shuffle(group);
+    }
+
+    let longest = groups.iter().map(|g| g.len()).max().unwrap_or(0);
+    (0..longest)
+        .flat_map(|round| groups.iter().filter_map(move |g| g.get(round).copied()))
+        .collect()
+}
+
+fn truncate_chars(text: &str, max_chars: usize) -> &str {
+    match text.char_indices().nth(max_chars) {
+        Some((index, _)) => &text[..index],
+        None => text,
+    }
+}
+
+/// Ask the chat model for questions about sampled chunks until `count` queries are
+/// collected, each expecting its chunk's `path:start-end` span. Questions too similar
+/// to an earlier one are skipped. Their embeddings go to the query embedding cache,
+/// where an eval run of the dataset finds them. Each query is written to `output` as a
+/// JSONL line once accepted, so an error keeps the ones already paid for.
+pub async fn generate_dataset(
+    store: &FileVectorStore,
+    api_key: &str,
+    options: &GenerateOptions,
+    output: &mut impl Write,
+    embeddings: &EmbeddingCache,
+) -> Result<Vec<EvalQuery>, Box<dyn std::error::Error>> {
+    let GenerateOptions {
+        count,
+        per_chunk,
+        ref strata,
+        max_similarity,
+        seed,
+    } = *options;
+    let mut random = Random::new(seed);
+    let mut dataset: Vec<EvalQuery> = Vec::new();
+    let mut accepted: Vec<Vec<f32>> = Vec::new();
+    let mut usage = ChatUsage::default();
+    let mut duplicates = 0;
+    let mut fruitless = 0;
+
+    let generated: Result<(), Box<dyn std::error::Error>> = async {
+        for chunk_index in sample_chunks(store, strata, &mut random) {
+            if dataset.len() >= count || fruitless >= MAX_FRUITLESS_CHUNKS {
+                break;
+            }
+            let chunk = &store.rag_vectors[chunk_index];
+            let Ok(contents) = std::fs::read_to_string(&chunk.filename) else {
+                continue;
+            };
+            let Some(text) = chunk.text(&contents) else {
+                continue;
+            };
+            if text.trim().len() < MIN_CHUNK_CHARS {
+                continue;
+            }
+            let Some(span) = crate::span::current_span(store, chunk, &contents) else {
+                continue;
+            };
+            let location = format!("{}:{}", chunk.filename, span);
+
+            let wanted = per_chunk.min(count - dataset.len());
+            let prompt = format!(
+                "Write {} question{}.\n\nFile: {}\n```\n{}\n```",
+                wanted,
+                if wanted == 1 { "" } else { "s" },
+                chunk.filename,
+                truncate_chars(text, MAX_SNIPPET_CHARS)
+            );
+            let (content, response_usage) = crate::openai::get_openai_json_completion(
+                &prompt,
+                GENERATE_SYSTEM_PROMPT,
+                "questions",
+                questions_schema(),
+                api_key,
+            )
+            .await?;
+            usage.prompt_tokens += response_usage.prompt_tokens;
+            usage.completion_tokens += response_usage.completion_tokens;
+            let questions: Questions = serde_json::from_str(&content)?;
+
+            let mut added = 0;
+            for question in questions.questions.into_iter().take(wanted) {
+                let question = question.trim().to_string();
+                if question.is_empty() {
+                    continue;
+                }
+                let vector = embeddings.embed(&question, api_key).await?;
+                let duplicate = accepted
+                    .iter()
+                    .any(|v| dot_product_similarity(&vector, v) >= max_similarity);
+                if duplicate {
+                    duplicates += 1;
+                    continue;
+                }
+                let query = EvalQuery {
+                    query: question,
+                    expected: vec![location.clone()],
+                };
+                writeln!(output, "{}", serde_json::to_string(&query)?)?;
+                output.flush()?;
+                accepted.push(vector);
+                dataset.push(query);
+                added += 1;
+            }
+            println!("{}: {} question(s)", location, added);
+            fruitless = if added == 0 { fruitless + 1 } else { 0 };
+        }
+        Ok(())
+    }
+    .await;
+
+    let config = crate::config::load_config();
+    let cost = crate::estimate::chat_cost(crate::openai::CHAT_MODEL, &usage, &config)
+        .map_or("unknown".to_string(), |cost| format!("${:.4}", cost));
+    eprintln!(
+        "Generate: {} prompt + {} completion tokens, cost {}",
+        usage.prompt_tokens, usage.completion_tokens, cost
+    );
+    if duplicates > 0 {
+        println!("Skipped {} near-duplicate question(s)", duplicates);
+    }
+    if let Err(e) = generated {
+        return Err(format!(
+            "{} (kept the {} queries written before it)",
+            e,
+            dataset.len()
+        )
+        .into());
+    }
+    Ok(dataset)
+}
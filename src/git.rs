@@ -0,0 +1,140 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::process::Command;
+
+pub struct GitFile {
+    pub path: String,
+    pub oid: String,
+}
+
+fn run_git(args: &[&str]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let output = Command::new("git").args(args).output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(output.stdout)
+}
+
+/// Same identity `git hash-object` gives a file's contents
+pub fn blob_oid(content: &[u8]) -> String {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(format!("blob {}\0", content.len()).as_bytes());
+    hasher.update(content);
+    hasher.digest().to_string()
+}
+
+/// Regular files tracked in the index, or in the tree of `rev` when given.
+/// Paths are relative to the current directory, like the filesystem walk.
+pub fn tracked_files(rev: Option<&str>) -> Result<Vec<GitFile>, Box<dyn Error>> {
+    let output = match rev {
+        Some(rev) => run_git(&["ls-tree", "-r", "-z", rev])?,
+        None => run_git(&["ls-files", "-s", "-z"])?,
+    };
+
+    let mut files = Vec::new();
+    let mut seen = HashSet::new();
+    for entry in output.split(|b| *b == 0).filter(|e| !e.is_empty()) {
+        let entry = String::from_utf8_lossy(entry);
+        let Some((info, path)) = entry.split_once('\t') else {
+            continue;
+        };
+        let fields: Vec<&str> = info.split_whitespace().collect();
+        // ls-tree: "<mode> <type> <oid>", ls-files -s: "<mode> <oid> <stage>"
+        let oid_field = match rev {
+            Some(_) => 2,
+            None => 1,
+        };
+        let (Some(mode), Some(oid)) = (fields.first(), fields.get(oid_field)) else {
+            continue;
+        };
+        // Skip symlinks (120000) and submodules (160000)
+        if *mode != "100644" && *mode != "100755" {
+            continue;
+        }
+        // Conflicted files are listed once per stage
+        if !seen.insert(path.to_string()) {
+            continue;
+        }
+        files.push(GitFile {
+            path: path.to_string(),
+            oid: oid.to_string(),
+        });
+    }
+
+    Ok(files)
+}
+
+pub fn read_blob(oid: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    run_git(&["cat-file", "blob", oid])
+}
+
+/// Committer timestamp of `rev` in seconds since the epoch
+pub fn commit_time(rev: &str) -> Result<u64, Box<dyn Error>> {
+    let output = run_git(&["log", "-1", "--format=%ct", rev])?;
+    Ok(String::from_utf8_lossy(&output).trim().parse()?)
+}
+
+pub fn resolve_rev(rev: &str) -> Result<String, Box<dyn Error>> {
+    let output = run_git(&["rev-parse", "--verify", &format!("{}^{{commit}}", rev)])?;
+    Ok(String::from_utf8_lossy(&output).trim().to_string())
+}
+
+/// Time of the most recent commit touching each file, relative to the current
+/// directory. Commits after `before` are ignored when it is given.
+pub fn last_commit_times(before: Option<u64>) -> Result<HashMap<String, u64>, Box<dyn Error>> {
+    let mut args = vec![
+        "log".to_string(),
+        "--relative".to_string(),
+        "--format=%x00%ct".to_string(),
+        "--name-only".to_string(),
+    ];
+    if let Some(before) = before {
+        args.push(format!("--before=@{}", before));
+    }
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    let output = run_git(&args)?;
+
+    let mut times = HashMap::new();
+    let mut commit_time = 0;
+    for line in String::from_utf8_lossy(&output).lines() {
+        if let Some(timestamp) = line.strip_prefix('\0') {
+            commit_time = timestamp.trim().parse()?;
+        } else if !line.is_empty() {
+            // Log is newest first, so keep the first time we see each file
+            times.entry(line.to_string()).or_insert(commit_time);
+        }
+    }
+
+    Ok(times)
+}
+
+/// Files changed by each of the last `max_commits` non-merge commits, newest first,
+/// relative to the current directory
+pub fn commit_changes(max_commits: usize) -> Result<Vec<Vec<String>>, Box<dyn Error>> {
+    let output = run_git(&[
+        "log",
+        "--relative",
+        "--no-merges",
+        "--format=%x00",
+        "--name-only",
+        &format!("--max-count={}", max_commits),
+    ])?;
+
+    let mut commits: Vec<Vec<String>> = Vec::new();
+    for line in String::from_utf8_lossy(&output).lines() {
+        if line.starts_with('\0') {
+            commits.push(Vec::new());
+        } else if !line.is_empty() {
+            if let Some(commit) = commits.last_mut() {
+                commit.push(line.to_string());
+            }
+        }
+    }
+
+    Ok(commits)
+}
@@ -0,0 +1,47 @@
+use clap::ValueEnum;
+use regex::Regex;
+use std::collections::HashSet;
+
+const HYDE_SYSTEM_PROMPT: &str = "You are an expert programmer. Given a question about a \
+codebase, write the code snippet that would most likely answer it, as it would appear in the \
+codebase. Use realistic function, type and variable names. Reply with code only, no explanation.";
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum HydeFusion {
+    /// Average the query and hypothetical document vectors
+    Average,
+    /// Take the better of the query and hypothetical document similarities per chunk
+    Max,
+}
+
+/// Ask the chat model for a hypothetical snippet answering the query (HyDE)
+pub async fn hypothetical_document(
+    query_text: &str,
+    api_key: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    crate::openai::get_openai_chat_completion(query_text, HYDE_SYSTEM_PROMPT, api_key).await
+}
+
+/// Identifiers that look like code rather than prose: snake_case, camelCase,
+/// PascalCase with an inner capital, or names called like functions
+pub fn extract_identifiers(text: &str) -> Vec<String> {
+    let identifier = Regex::new(r"[A-Za-z_][A-Za-z0-9_]*(\s*\()?").unwrap();
+    let mut seen = HashSet::new();
+    let mut identifiers = Vec::new();
+
+    for m in identifier.find_iter(text) {
+        let called = m.as_str().ends_with('(');
+        let name = m.as_str().trim_end_matches('(').trim_end();
+        let looks_like_code =
+            name.contains('_') || name.chars().skip(1).any(|c| c.is_ascii_uppercase()) || called;
+        if name.len() > 2 && looks_like_code && seen.insert(name.to_string()) {
+            identifiers.push(name.to_string());
+        }
+    }
+
+    identifiers
+}
+
+pub fn average_vectors(a: &[f32], b: &[f32]) -> Vec<f32> {
+    a.iter().zip(b.iter()).map(|(x, y)| (x + y) / 2.0).collect()
+}
@@ -0,0 +1,235 @@
+use crate::scan::{Bm25EmbeddedFile, FileVectorStore, ScanSource, SourceFile, VECTORS_FILE};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileStatus {
+    New,
+    Modified,
+    Deleted,
+}
+
+impl FileStatus {
+    fn label(&self) -> &'static str {
+        match self {
+            FileStatus::New => "new",
+            FileStatus::Modified => "modified",
+            FileStatus::Deleted => "deleted",
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// The scan command that rebuilds the index with its current settings
+pub fn rescan_command(store: &FileVectorStore) -> String {
+    let mut command = format!("luckyshot scan --pattern \"{}\"", store.pattern);
+    if store.chunk_size > 0 {
+        command.push_str(&format!(
+            " --chunk-size {} --chunk-overlap {}",
+            store.chunk_size, store.overlap_size
+        ));
+    }
+    if store.embed_metadata {
+        command.push_str(" --embed-metadata");
+    }
+    if let Some(rev) = &store.git_rev {
+        command.push_str(&format!(" --rev {}", rev));
+    }
+    command
+}
+
+/// What `info` reports about the index
+#[derive(Serialize)]
+pub struct IndexInfo {
+    pub pattern: String,
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    pub embed_metadata: bool,
+    pub scanned: u64, // Seconds since the epoch
+    pub git_rev: Option<String>,
+    pub files: usize,
+    pub chunks: usize,
+    pub vector_dimension: usize,
+    pub symbols: usize,
+    pub files_with_imports: usize,
+    pub index_bytes: Option<u64>,
+    pub avgdl: f32,
+    pub largest_files: Vec<LargeFile>,
+}
+
+#[derive(Serialize)]
+pub struct LargeFile {
+    pub filename: String,
+    pub tokens: usize,
+    pub chunks: usize,
+}
+
+/// Settings, sizes and the `top` largest files of the index
+pub fn index_info(store: &FileVectorStore, top: usize) -> IndexInfo {
+    let mut chunk_counts: HashMap<&str, usize> = HashMap::new();
+    for chunk in &store.rag_vectors {
+        *chunk_counts.entry(chunk.filename.as_str()).or_default() += 1;
+    }
+    let mut largest: Vec<&Bm25EmbeddedFile> = store.bm25_files.iter().collect();
+    largest.sort_by_key(|f| std::cmp::Reverse(f.token_count));
+
+    IndexInfo {
+        pattern: store.pattern.clone(),
+        chunk_size: store.chunk_size,
+        chunk_overlap: store.overlap_size,
+        embed_metadata: store.embed_metadata,
+        scanned: store.date,
+        git_rev: store.git_rev.clone(),
+        files: store.bm25_files.len(),
+        chunks: store.rag_vectors.len(),
+        vector_dimension: store.rag_vectors.first().map_or(0, |c| c.vector.len()),
+        symbols: store.symbols.len(),
+        files_with_imports: store.dependencies.len(),
+        index_bytes: std::fs::metadata(VECTORS_FILE).ok().map(|m| m.len()),
+        avgdl: store.bm25_avgdl,
+        largest_files: largest
+            .iter()
+            .take(top)
+            .map(|f| LargeFile {
+                filename: f.filename.clone(),
+                tokens: f.token_count,
+                chunks: chunk_counts.get(f.filename.as_str()).copied().unwrap_or(0),
+            })
+            .collect(),
+    }
+}
+
+pub fn print_info(info: &IndexInfo) {
+    println!("Pattern: {}", info.pattern);
+    if info.chunk_size > 0 {
+        println!("Chunk size: {}", info.chunk_size);
+        println!("Chunk overlap: {}", info.chunk_overlap);
+    } else {
+        println!("Chunk size: 0 (whole files)");
+    }
+    println!("Metadata embedded: {}", info.embed_metadata);
+    println!(
+        "Scanned: {}",
+        crate::recency::format_timestamp(info.scanned)
+    );
+    if let Some(rev) = &info.git_rev {
+        println!("Git revision: {}", rev);
+    }
+
+    println!();
+    println!("Files: {}", info.files);
+    println!("Chunks: {}", info.chunks);
+    println!("Vector dimension: {}", info.vector_dimension);
+    println!("Symbols: {}", info.symbols);
+    println!("Files with imports: {}", info.files_with_imports);
+    match info.index_bytes {
+        Some(bytes) => println!("Index size: {}", format_bytes(bytes)),
+        None => println!("Index size: unknown"),
+    }
+    println!("Average document length: {:.2} tokens", info.avgdl);
+
+    if !info.largest_files.is_empty() {
+        println!("\nLargest files:");
+        println!("Tokens,Chunks,File");
+        for file in &info.largest_files {
+            println!("{},{},{}", file.tokens, file.chunks, file.filename);
+        }
+    }
+}
+
+// Whether a file differs from what was indexed. Files indexed before blob ids were
+// recorded fall back to the modification time, which metadata embeddings also include.
+fn is_modified(indexed: &Bm25EmbeddedFile, file: &SourceFile, embed_metadata: bool) -> bool {
+    if indexed.blob_oid.is_empty() {
+        return indexed.last_modified != file.last_modified;
+    }
+    indexed.blob_oid != file.blob_oid
+        || (embed_metadata && indexed.last_modified != file.last_modified)
+}
+
+/// Files matching the index's pattern that are new, modified or deleted in the working
+/// tree since the scan, sorted by path
+pub fn index_status(
+    store: &FileVectorStore,
+) -> Result<Vec<(FileStatus, String)>, Box<dyn std::error::Error>> {
+    let current = crate::scan::collect_source_files(&store.pattern, &ScanSource::Walk)?;
+    let indexed: HashMap<&str, &Bm25EmbeddedFile> = store
+        .bm25_files
+        .iter()
+        .map(|f| (f.filename.as_str(), f))
+        .collect();
+
+    let mut changes = Vec::new();
+    for file in &current {
+        match indexed.get(file.path_str.as_str()) {
+            None => changes.push((FileStatus::New, file.path_str.clone())),
+            Some(indexed) if is_modified(indexed, file, store.embed_metadata) => {
+                changes.push((FileStatus::Modified, file.path_str.clone()))
+            }
+            Some(_) => {}
+        }
+    }
+    // Files added with --force don't match the pattern, but may still exist
+    let present: HashSet<&str> = current.iter().map(|f| f.path_str.as_str()).collect();
+    for indexed in store
+        .bm25_files
+        .iter()
+        .filter(|f| !present.contains(f.filename.as_str()))
+    {
+        let path = std::path::Path::new(&indexed.filename);
+        match crate::scan::read_working_file(path, indexed.filename.clone()) {
+            Ok(file) if is_modified(indexed, &file, store.embed_metadata) => {
+                changes.push((FileStatus::Modified, indexed.filename.clone()))
+            }
+            Ok(_) => {}
+            Err(_) => changes.push((FileStatus::Deleted, indexed.filename.clone())),
+        }
+    }
+
+    changes.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(changes)
+}
+
+pub fn print_status(store: &FileVectorStore, changes: &[(FileStatus, String)]) {
+    println!(
+        "Index of {} files matching \"{}\", scanned {}",
+        store.bm25_files.len(),
+        store.pattern,
+        crate::recency::format_timestamp(store.date)
+    );
+    if let Some(rev) = &store.git_rev {
+        println!("Built from commit {}, compared with the working tree", rev);
+    }
+
+    if changes.is_empty() {
+        println!("Up to date");
+        return;
+    }
+    println!();
+    for (status, filename) in changes {
+        println!("{:<9} {}", format!("{}:", status.label()), filename);
+    }
+    let count = |wanted: FileStatus| changes.iter().filter(|(s, _)| *s == wanted).count();
+    println!(
+        "\n{} new, {} modified, {} deleted. Rescan with: {}",
+        count(FileStatus::New),
+        count(FileStatus::Modified),
+        count(FileStatus::Deleted),
+        rescan_command(store)
+    );
+}
@@ -0,0 +1,530 @@
+use crate::cache::{EmbeddingCache, EMBEDDING_CACHE_FILE, MAX_QUERY_EMBEDDINGS};
+use crate::scan::FileVectorStore;
+use crate::search::{FileMatch, SearchOptions, WeightedQuery};
+use crate::serve::{LoadedIndex, OptionsParser};
+use crate::similarity::dot_product_similarity;
+use crate::span::LineSpan;
+use glob_match::glob_match;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+const RELATED_FILES_COMMAND: &str = "luckyshot.relatedFiles";
+const SEARCH_COMMAND: &str = "luckyshot.search";
+
+// Files named in a code lens
+const LENS_FILES: usize = 3;
+
+// Keep file-sized queries within what the embedding model accepts
+const MAX_QUERY_CHARS: usize = 8000;
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+const SERVER_NOT_INITIALIZED: i64 = -32002;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl std::fmt::Display) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+struct LanguageServer {
+    index: Option<LoadedIndex>, // Loaded on initialize, from the workspace root
+    options: SearchOptions,
+    open_documents: HashMap<String, String>, // Text of open documents by URI
+    api_key: String,
+    parse_options: OptionsParser,
+    rescan_lock: Arc<Mutex<()>>, // One rescan writes the index at a time
+    embeddings: Arc<EmbeddingCache>, // Read on first use, after moving to the workspace root
+}
+
+// The absolute path of a file URI
+fn decode_uri(uri: &str) -> Option<String> {
+    let path = uri.strip_prefix("file://")?;
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| path.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// A file URI's path as the index stores it
+fn uri_to_path(uri: &str) -> Option<String> {
+    Some(crate::update::normalize_path(&decode_uri(uri)?))
+}
+
+fn path_to_uri(filename: &str) -> String {
+    let path: PathBuf = std::env::current_dir().unwrap_or_default().join(filename);
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                uri.push(byte as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+/// An LSP `Location` for the span, which is 1-based where LSP positions are 0-based
+fn location(filename: &str, span: LineSpan) -> Value {
+    let start_line = span.start_line.max(1) - 1;
+    let end_line = span.end_line.max(span.start_line).max(1) - 1;
+    json!({
+        "uri": path_to_uri(filename),
+        "range": {
+            "start": { "line": start_line, "character": span.start_column.saturating_sub(1) },
+            "end": { "line": end_line, "character": span.end_column },
+        },
+    })
+}
+
+// The text between two LSP positions, counting characters rather than UTF-16 units
+fn range_text(text: &str, range: &Value) -> Option<String> {
+    let position = |key: &str| -> Option<(usize, usize)> {
+        let line = range[key]["line"].as_u64()? as usize;
+        let character = range[key]["character"].as_u64()? as usize;
+        Some((line, character))
+    };
+    let (start, end) = (position("start")?, position("end")?);
+    let selected: Vec<String> = text
+        .lines()
+        .enumerate()
+        .skip(start.0)
+        .take(end.0 + 1 - start.0.min(end.0))
+        .map(|(i, line)| {
+            let from = if i == start.0 { start.1 } else { 0 };
+            let to = if i == end.0 { end.1 } else { usize::MAX };
+            line.chars()
+                .skip(from)
+                .take(to.saturating_sub(from))
+                .collect()
+        })
+        .collect();
+    Some(selected.join("\n"))
+}
+
+/// Files whose chunks are closest to any chunk of `filename`, from the stored
+/// embeddings, so no API call is needed. Each file scores its closest chunk.
+fn related_by_vectors(store: &FileVectorStore, filename: &str, count: usize) -> Vec<FileMatch> {
+    let own: Vec<&[f32]> = store
+        .rag_vectors
+        .iter()
+        .filter(|c| c.filename == filename)
+        .map(|c| c.vector.as_slice())
+        .collect();
+    let mut best: HashMap<&str, (f32, usize)> = HashMap::new();
+    for (chunk_index, chunk) in store.rag_vectors.iter().enumerate() {
+        if chunk.filename == filename {
+            continue;
+        }
+        let similarity = own
+            .iter()
+            .map(|v| dot_product_similarity(v, &chunk.vector))
+            .fold(f32::MIN, f32::max);
+        let entry = best
+            .entry(chunk.filename.as_str())
+            .or_insert((similarity, chunk_index));
+        if similarity > entry.0 {
+            *entry = (similarity, chunk_index);
+        }
+    }
+
+    let mut related: Vec<FileMatch> = best
+        .into_iter()
+        .map(|(filename, (similarity, chunk_index))| FileMatch {
+            filename: filename.to_string(),
+            similarity,
+            chunk_index,
+            expansion: None,
+            components: Default::default(),
+        })
+        .collect();
+    related.sort_by(|a, b| b.similarity.partial_cmp(&a.similarity).unwrap());
+    related.truncate(count);
+    related
+}
+
+impl LanguageServer {
+    fn index(&mut self) -> Result<&LoadedIndex, RpcError> {
+        let index = self.index.as_mut().ok_or_else(|| {
+            RpcError::new(SERVER_NOT_INITIALIZED, "The server is not initialized")
+        })?;
+        if index.is_stale() {
+            index.reload();
+        }
+        Ok(index)
+    }
+
+    fn document_text(&self, uri: &str, path: &str) -> Result<String, RpcError> {
+        match self.open_documents.get(uri) {
+            Some(text) => Ok(text.clone()),
+            None => std::fs::read_to_string(path)
+                .map_err(|e| RpcError::new(INTERNAL_ERROR, format!("{}: {}", path, e))),
+        }
+    }
+
+    fn initialize(&mut self, params: &Value) -> Result<Value, RpcError> {
+        // The index lives in the workspace root, and stores paths relative to it
+        if let Some(root) = params["rootUri"].as_str().and_then(decode_uri) {
+            std::env::set_current_dir(&root)
+                .map_err(|e| RpcError::new(INTERNAL_ERROR, format!("{}: {}", root, e)))?;
+        }
+        // Parsed here rather than at startup, so the config file is the workspace's
+        let args = match params["initializationOptions"].as_object() {
+            Some(knobs) => crate::serve::knob_args(knobs),
+            None => Ok(Vec::new()),
+        };
+        self.options = args
+            .and_then(|args| (self.parse_options)(&args))
+            .map_err(|e| RpcError::new(INVALID_PARAMS, e))?;
+        let index = LoadedIndex::load().map_err(|e| {
+            RpcError::new(
+                INTERNAL_ERROR,
+                format!("No luckyshot index here, run `luckyshot scan` first: {}", e),
+            )
+        })?;
+        eprintln!(
+            "Loaded {} files from {}",
+            index.store.bm25_files.len(),
+            std::env::current_dir().unwrap_or_default().display()
+        );
+        self.index = Some(index);
+
+        Ok(json!({
+            "capabilities": {
+                "textDocumentSync": {
+                    "openClose": true,
+                    "change": 1, // Full text
+                    "save": { "includeText": false },
+                },
+                "codeLensProvider": { "resolveProvider": false },
+                "executeCommandProvider": {
+                    "commands": [RELATED_FILES_COMMAND, SEARCH_COMMAND],
+                },
+            },
+            "serverInfo": { "name": "luckyshot", "version": env!("CARGO_PKG_VERSION") },
+        }))
+    }
+
+    async fn search(&mut self, query: &str, exclude: Option<&str>) -> Result<Value, RpcError> {
+        let queries = [WeightedQuery::new(query)];
+        let count = self.options.count;
+        let mut options = self.options.clone();
+        // One more in case the excluded file is among the hits
+        if exclude.is_some() && count > 0 {
+            options.count += 1;
+        }
+        let api_key = self.api_key.clone();
+        let embeddings = Arc::clone(&self.embeddings);
+        let index = self.index()?;
+        let ranked =
+            crate::search::rank_files(&index.store, &queries, &api_key, &options, &embeddings)
+                .await;
+        if let Err(e) = embeddings.save() {
+            eprintln!("Error writing embedding cache: {}", e);
+        }
+        let mut matches = ranked.map_err(|e| RpcError::new(INTERNAL_ERROR, e))?;
+        if let Some(exclude) = exclude {
+            matches.retain(|m| m.filename != exclude);
+            // Drop the extra hit if it wasn't needed, expansions come after the hits
+            let hits = matches.iter().filter(|m| m.expansion.is_none()).count();
+            if count > 0 && hits > count {
+                matches.remove(count);
+            }
+        }
+        let locations: Vec<Value> = crate::serve::search_results(index, &matches)
+            .iter()
+            .map(|r| location(r.filename, r.span.unwrap_or_default()))
+            .collect();
+        Ok(json!(locations))
+    }
+
+    // The current file is the query, or the selection in it when a range is given
+    async fn related_files(&mut self, arguments: &[Value]) -> Result<Value, RpcError> {
+        let uri = arguments
+            .first()
+            .and_then(|a| a.as_str())
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Expected a document URI"))?;
+        let path = uri_to_path(uri)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("Not a file: {}", uri)))?;
+        let count = self.options.count;
+
+        let selection = match arguments.get(1) {
+            Some(range) if range.is_object() => {
+                range_text(&self.document_text(uri, &path)?, range).filter(|s| !s.trim().is_empty())
+            }
+            _ => None,
+        };
+        let indexed = self
+            .index()?
+            .store
+            .bm25_files
+            .iter()
+            .any(|f| f.filename == path);
+        let query = match selection {
+            Some(selection) => selection,
+            None if indexed => {
+                let index = self.index()?;
+                let related = related_by_vectors(&index.store, &path, count);
+                let locations: Vec<Value> = crate::serve::search_results(index, &related)
+                    .iter()
+                    .map(|r| location(r.filename, r.span.unwrap_or_default()))
+                    .collect();
+                return Ok(json!(locations));
+            }
+            None => self
+                .document_text(uri, &path)?
+                .chars()
+                .take(MAX_QUERY_CHARS)
+                .collect(),
+        };
+        self.search(&query, Some(&path)).await
+    }
+
+    async fn execute_command(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let command = params["command"].as_str().unwrap_or_default();
+        let arguments = params["arguments"].as_array().cloned().unwrap_or_default();
+        match command {
+            RELATED_FILES_COMMAND => self.related_files(&arguments).await,
+            SEARCH_COMMAND => {
+                let query = arguments
+                    .first()
+                    .and_then(|a| a.as_str())
+                    .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Expected a query"))?;
+                self.search(query, None).await
+            }
+            _ => Err(RpcError::new(
+                INVALID_PARAMS,
+                format!("Unknown command: {}", command),
+            )),
+        }
+    }
+
+    // One lens at the top of each indexed file, naming its closest files
+    fn code_lens(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let Some(path) = uri_to_path(uri) else {
+            return Ok(json!([]));
+        };
+        let store = &self.index()?.store;
+        let related = related_by_vectors(store, &path, LENS_FILES);
+        if related.is_empty() {
+            return Ok(json!([]));
+        }
+        let names: Vec<&str> = related
+            .iter()
+            .map(|m| m.filename.rsplit('/').next().unwrap_or(&m.filename))
+            .collect();
+        Ok(json!([{
+            "range": {
+                "start": { "line": 0, "character": 0 },
+                "end": { "line": 0, "character": 0 },
+            },
+            "command": {
+                "title": format!("Related: {}", names.join(", ")),
+                "command": RELATED_FILES_COMMAND,
+                "arguments": [uri],
+            },
+        }]))
+    }
+
+    async fn handle_request(&mut self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        if self.index.is_none() && method != "initialize" && method != "shutdown" {
+            return Err(RpcError::new(
+                SERVER_NOT_INITIALIZED,
+                "The server is not initialized",
+            ));
+        }
+        match method {
+            "initialize" => self.initialize(params),
+            "shutdown" => Ok(Value::Null),
+            "workspace/executeCommand" => self.execute_command(params).await,
+            "textDocument/codeLens" => self.code_lens(params),
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method: {}", method),
+            )),
+        }
+    }
+
+    // Saved files matching the index's pattern are reindexed in the background, the
+    // next request picks up the rewritten index
+    fn did_save(&mut self, uri: &str) {
+        let Some(index) = &self.index else {
+            return;
+        };
+        let Some(path) = uri_to_path(uri) else {
+            return;
+        };
+        let store = &index.store;
+        let indexed = store.bm25_files.iter().any(|f| f.filename == path);
+        if store.git_rev.is_some() || (!indexed && !glob_match(&store.pattern, &path)) {
+            return;
+        }
+        let lock = self.rescan_lock.clone();
+        // Files indexed outside the pattern were added with --force
+        tokio::task::spawn_blocking(move || {
+            let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+            match crate::update::rescan_in_child(std::slice::from_ref(&path), indexed) {
+                Ok(summary) => eprintln!("{}: {}", path, summary),
+                Err(e) => eprintln!("{}: {}", path, e),
+            }
+        });
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &Value) {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.open_documents.insert(uri, text.to_string());
+            }
+            "textDocument/didChange" => {
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes
+                    .and_then(|c| c.last())
+                    .and_then(|c| c["text"].as_str())
+                {
+                    self.open_documents.insert(uri, text.to_string());
+                }
+            }
+            "textDocument/didClose" => {
+                self.open_documents.remove(&uri);
+            }
+            "textDocument/didSave" => self.did_save(&uri),
+            _ => {}
+        }
+    }
+}
+
+// Messages are framed by a Content-Length header, as in HTTP
+async fn read_message<R: AsyncBufReadExt + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<Option<Result<Value, String>>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = Some(value.trim().parse::<usize>());
+            }
+        }
+    }
+    let length = match length {
+        Some(Ok(length)) => length,
+        Some(Err(e)) => return Ok(Some(Err(format!("Invalid Content-Length: {}", e)))),
+        None => return Ok(Some(Err("Message without Content-Length".to_string()))),
+    };
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(
+        serde_json::from_slice(&body).map_err(|e| e.to_string()),
+    ))
+}
+
+fn send(message: &Value) -> std::io::Result<()> {
+    let body = message.to_string();
+    let mut stdout = std::io::stdout().lock();
+    write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    stdout.flush()
+}
+
+/// Serve editors over stdio until they send `exit`. Logs go to stderr.
+pub async fn run(
+    api_key: String,
+    parse_options: OptionsParser,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut server = LanguageServer {
+        index: None,
+        options: SearchOptions::default(),
+        open_documents: HashMap::new(),
+        api_key,
+        parse_options,
+        rescan_lock: Arc::new(Mutex::new(())),
+        embeddings: Arc::new(EmbeddingCache::load(
+            EMBEDDING_CACHE_FILE,
+            MAX_QUERY_EMBEDDINGS,
+        )),
+    };
+    let mut reader = BufReader::new(tokio::io::stdin());
+    while let Some(message) = read_message(&mut reader).await? {
+        // A bad message is answered, only a closed or failing stdin ends the server
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Unreadable message: {}", e);
+                send(&json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": { "code": PARSE_ERROR, "message": e },
+                }))?;
+                continue;
+            }
+        };
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = message.get("params").cloned().unwrap_or(json!({}));
+        let Some(id) = message.get("id").cloned() else {
+            if method == "exit" {
+                break;
+            }
+            server.handle_notification(method, &params);
+            continue;
+        };
+        // Responses to requests we never send
+        if message.get("method").is_none() {
+            continue;
+        }
+
+        let response = match server.handle_request(method, &params).await {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": e.code, "message": e.message },
+            }),
+        };
+        send(&response)?;
+    }
+    Ok(())
+}
@@ -1,17 +1,48 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use dotenvy::dotenv;
 use std::env;
 use std::io::Read;
+use std::sync::OnceLock;
 
+mod aider;
+mod ask;
 mod bm25_embedder;
 mod bm25_ranker;
+mod cache;
+mod cochange;
+mod config;
+mod deps;
+mod diff;
+mod edit;
+mod emit;
+mod estimate;
+mod eval;
+mod expand;
 mod files;
+mod generate;
+mod git;
+mod hyde;
+mod info;
+mod lsp;
+mod mcp;
 mod metadata;
 mod openai;
+mod pack;
+mod preference;
+mod random;
+mod recency;
+mod regex_boost;
+mod rerank;
 mod scan;
 mod search;
+mod serve;
 mod similarity;
+mod span;
+mod symbols;
+mod token_count;
 mod tokenizer;
+mod tune;
+mod update;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -20,32 +51,327 @@ struct Cli {
     command: Commands,
 }
 
+/// Ranking knobs shared by every command that searches the index
+#[derive(Args)]
+struct RankingArgs {
+    /// Scale factor for BM25 score influence (default 0.1, or the config's)
+    #[arg(long)]
+    bm25_scale: Option<f32>,
+
+    /// Scale factor for RAG score influence (default 1.0, or the config's)
+    #[arg(long)]
+    rag_scale: Option<f32>,
+
+    /// How to combine the RAG, BM25 and recency scores (default weighted, or the config's)
+    #[arg(long, value_enum)]
+    fusion: Option<search::Fusion>,
+
+    /// How to score a file from its chunks (default max, or the config's)
+    #[arg(long, value_enum)]
+    aggregation: Option<search::Aggregation>,
+
+    /// Scale factor for recency score influence (default 0.0, off)
+    #[arg(long, default_value = "0.0")]
+    recency_scale: f32,
+
+    /// Days from the as-of date after which the recency score halves
+    #[arg(long, default_value = "30.0")]
+    recency_half_life: f32,
+
+    /// Date recency is measured from, YYYY-MM-DD or seconds since epoch (default now)
+    #[arg(long, value_parser = recency::parse_as_of)]
+    as_of: Option<u64>,
+
+    /// Use each file's last git commit time for recency instead of its modification time
+    #[arg(long, default_value = "false")]
+    recency_from_git: bool,
+
+    /// Boost files by regex match count, as `<regex>` or `<regex>=<weight>` (repeatable)
+    #[arg(long, value_parser = regex_boost::parse_boost_regex)]
+    boost_regex: Vec<regex_boost::BoostRegex>,
+
+    /// Scale factor for regex match count influence (default 0.5)
+    #[arg(long, default_value = "0.5")]
+    regex_scale: f32,
+
+    /// Only count regex matches for this many top candidates (0 for all)
+    #[arg(long, default_value = "0")]
+    boost_regex_top: usize,
+
+    /// Score multipliers by extension or path glob, e.g. `rs=1.2,md=0.6` or `path:src/**=1.1`
+    #[arg(long, value_delimiter = ',', value_parser = preference::parse_preference)]
+    prefer: Vec<preference::Preference>,
+
+    /// Score multiplier for test files like tests/** and *_test.* (default 1.0, or from config)
+    #[arg(long)]
+    test_weight: Option<f32>,
+
+    /// Have the chat model grade and reorder this many top candidates (0 for off)
+    #[arg(long, default_value = "0")]
+    rerank: usize,
+
+    /// Keep only reranked candidates graded at least this (0 to 10), dropping the ungraded rest
+    #[arg(long)]
+    rerank_min_grade: Option<u32>,
+
+    /// Also search with a hypothetical answer written by the chat model (HyDE)
+    #[arg(long, default_value = "false")]
+    hyde: bool,
+
+    /// How to combine the query and hypothetical answer embeddings
+    #[arg(long, value_enum, default_value = "average")]
+    hyde_fusion: hyde::HydeFusion,
+
+    /// Push away results similar to this, as `<prompt>` or `<prompt>^<weight>` (repeatable)
+    #[arg(long = "not", value_parser = search::parse_weighted_query)]
+    not: Vec<search::WeightedQuery>,
+
+    /// Add up to this many files that often changed in the same commits as the top hits
+    #[arg(long, default_value = "0")]
+    expand_cochange: usize,
+
+    /// Weight of co-change expansions relative to the hit they were found from
+    #[arg(long, default_value = "0.5")]
+    cochange_weight: f32,
+
+    /// Add up to this many files that the top hits import or are imported by
+    #[arg(long, default_value = "0")]
+    expand_deps: usize,
+
+    /// Weight of dependency expansions relative to the hit they were found from
+    #[arg(long, default_value = "0.5")]
+    deps_weight: f32,
+}
+
+/// The config file with its preferences parsed
+struct RankingConfig {
+    config: config::Config,
+    preferences: Vec<preference::Preference>,
+}
+
+/// The config file, read and checked on first use. Servers parse search options per
+/// request, so they read it once rather than for every search.
+fn ranking_config() -> Result<&'static RankingConfig, String> {
+    static RANKING_CONFIG: OnceLock<Result<RankingConfig, String>> = OnceLock::new();
+    RANKING_CONFIG
+        .get_or_init(|| {
+            let config = config::load_config();
+            let preferences = config
+                .prefer
+                .iter()
+                .map(|p| preference::parse_preference(p))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Error in config prefer: {}", e))?;
+            Ok(RankingConfig {
+                config,
+                preferences,
+            })
+        })
+        .as_ref()
+        .map_err(|e| e.clone())
+}
+
+impl RankingArgs {
+    /// Search options with these ranking knobs, merged with the config file
+    fn search_options(self) -> Result<search::SearchOptions, String> {
+        let RankingConfig {
+            config,
+            preferences: config_preferences,
+        } = ranking_config()?;
+
+        let defaults = search::SearchOptions::default();
+        Ok(search::SearchOptions {
+            bm25_scale: self
+                .bm25_scale
+                .or(config.bm25_scale)
+                .unwrap_or(defaults.bm25_scale),
+            rag_scale: self
+                .rag_scale
+                .or(config.rag_scale)
+                .unwrap_or(defaults.rag_scale),
+            fusion: self.fusion.or(config.fusion).unwrap_or(defaults.fusion),
+            aggregation: self
+                .aggregation
+                .or(config.aggregation)
+                .unwrap_or(defaults.aggregation),
+            recency_scale: self.recency_scale,
+            recency: recency::RecencyOptions {
+                half_life_days: self.recency_half_life,
+                as_of: self.as_of,
+                from_git: self.recency_from_git,
+            },
+            boost_regexes: self.boost_regex,
+            regex_scale: self.regex_scale,
+            boost_regex_top: self.boost_regex_top,
+            preferences: preference::merge_preferences(config_preferences, &self.prefer),
+            test_weight: self.test_weight.or(config.test_weight).unwrap_or(1.0),
+            rerank: self.rerank,
+            rerank_min_grade: self.rerank_min_grade,
+            hyde: self.hyde,
+            hyde_fusion: self.hyde_fusion,
+            negative_queries: self.not,
+            expand_cochange: self.expand_cochange,
+            cochange_weight: self.cochange_weight,
+            expand_deps: self.expand_deps,
+            deps_weight: self.deps_weight,
+            ..Default::default()
+        })
+    }
+}
+
+/// Settings an eval run compares: the similarity filter and the ranking knobs
+#[derive(Args)]
+struct EvalConfigArgs {
+    /// Only count results with similarity >= filter-similarity (0.0 to 1.0)
+    #[arg(short, long, default_value = "0.0")]
+    filter_similarity: f32,
+
+    #[command(flatten)]
+    ranking: RankingArgs,
+}
+
+impl EvalConfigArgs {
+    fn search_options(self) -> Result<search::SearchOptions, String> {
+        Ok(search::SearchOptions {
+            filter_similarity: self.filter_similarity,
+            ..self.ranking.search_options()?
+        })
+    }
+}
+
+/// Ranking knobs of a `serve`, `mcp` or `lsp` search request, parsed from its JSON fields as flags
+#[derive(Parser)]
+#[command(name = "serve search", no_binary_name = true)]
+struct ServeSearchArgs {
+    /// Limit the number of results (0 for unlimited)
+    #[arg(short, long, default_value = "10")]
+    count: usize,
+
+    #[command(flatten)]
+    config: EvalConfigArgs,
+}
+
+fn serve_search_options(args: &[String]) -> Result<search::SearchOptions, String> {
+    let args = ServeSearchArgs::try_parse_from(args).map_err(|e| {
+        let message = e.to_string();
+        message.lines().next().unwrap_or_default().to_string()
+    })?;
+    Ok(search::SearchOptions {
+        count: args.count,
+        ..args.config.search_options()?
+    })
+}
+
+/// A second eval configuration, parsed from the `--compare` flags
+#[derive(Parser)]
+#[command(name = "--compare", no_binary_name = true)]
+struct CompareArgs {
+    #[command(flatten)]
+    config: EvalConfigArgs,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Scan files matching a glob pattern
     Scan {
         /// The glob pattern to match files
-        #[arg(short, long, required = true)]
-        pattern: String,
+        #[arg(short, long, required_unless_present = "stdin")]
+        pattern: Option<String>,
 
-        /// Size of chunks to split files into (0 for no chunking)
-        #[arg(long, default_value = "0")]
-        chunk_size: usize,
+        /// Size of chunks to split files into (0 for no chunking, default 0 or the config's)
+        #[arg(long)]
+        chunk_size: Option<usize>,
 
-        /// Size of overlap between chunks (0 for no overlap)
-        #[arg(long, default_value = "0")]
-        chunk_overlap: usize,
+        /// Size of overlap between chunks (0 for no overlap, default 0 or the config's)
+        #[arg(long)]
+        chunk_overlap: Option<usize>,
 
         /// Include file metadata in embeddings
         #[arg(long, default_value = "false")]
         embed_metadata: bool,
+
+        /// Report files, chunks, tokens and estimated cost without calling the API
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+
+        /// Refuse to scan if the estimated embedding cost in USD is above this
+        #[arg(long)]
+        max_cost: Option<f64>,
+
+        /// Only scan files tracked in the git index
+        #[arg(long, default_value = "false")]
+        git: bool,
+
+        /// Index a committed snapshot (e.g. HEAD~3) without touching the working tree
+        #[arg(long)]
+        rev: Option<String>,
+
+        /// Update the existing index in place with the files listed on stdin, one per line
+        /// (e.g. from `git ls-files`). Listed files that no longer exist are removed.
+        #[arg(long, default_value = "false", conflicts_with_all = ["pattern", "chunk_size", "chunk_overlap", "embed_metadata", "dry_run", "max_cost", "git", "rev"])]
+        stdin: bool,
+
+        /// With --stdin, also index files that don't match the index's pattern
+        #[arg(long, default_value = "false", requires = "stdin")]
+        force: bool,
+    },
+
+    /// Add files to the existing index, or update them, without a full rescan
+    Add {
+        /// Files to index
+        #[arg(required = true)]
+        paths: Vec<String>,
+
+        /// Index files that don't match the index's pattern
+        #[arg(long, default_value = "false")]
+        force: bool,
+    },
+
+    /// Remove files from the existing index without a full rescan
+    Rm {
+        /// Files to remove
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+
+    /// Show the index's settings, size and largest files
+    Info {
+        /// Number of largest files to list
+        #[arg(long, default_value = "10")]
+        top: usize,
     },
 
+    /// List files that are new, modified or deleted since the last scan
+    Status,
+
+    /// Serve the index over a local HTTP JSON API for search, index info and updates
+    Serve {
+        /// Port to listen on
+        #[arg(short, long, default_value = "8484")]
+        port: u16,
+
+        /// Address to bind; anything but loopback requires --token
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+
+        /// Require `Authorization: Bearer <token>` on every request
+        #[arg(long)]
+        token: Option<String>,
+    },
+
+    /// Run a Model Context Protocol server over stdio, for coding agents to search the index
+    Mcp,
+
+    /// Run a Language Server Protocol server over stdio, for related files and semantic
+    /// search in editors
+    Lsp,
+
     /// Suggest relevant files based on a query
     SuggestFiles {
-        /// The query to find relevant files (optional if using stdin)
-        #[arg(short, long, required = false)]
-        prompt: Option<String>,
+        /// The query to find relevant files, as `<prompt>` or `<prompt>^<weight>`
+        /// (repeatable, optional if using stdin)
+        #[arg(short, long, required = false, value_parser = search::parse_weighted_query)]
+        prompt: Vec<search::WeightedQuery>,
 
         /// Only return results with similarity >= filter-similarity (0.0 to 1.0)
         #[arg(short, long, default_value = "0.0")]
@@ -67,13 +393,158 @@ enum Commands {
         #[arg(short, long, default_value = "0")]
         count: usize,
 
-        /// Scale factor for BM25 score influence (default 0.1)
-        #[arg(long, default_value = "0.1")]
-        bm25_scale: f32,
+        /// Write the results as context for a coding agent instead of printing them
+        #[arg(long, value_enum, conflicts_with_all = ["verbose", "file_contents"])]
+        emit: Option<emit::EmitFormat>,
+
+        /// Emitted files scoring >= edit-threshold are editable, the rest read-only
+        #[arg(long, default_value = "0.0", requires = "emit")]
+        edit_threshold: f32,
 
-        /// Scale factor for RAG score influence (default 1.0)
-        #[arg(long, default_value = "1.0")]
-        rag_scale: f32,
+        /// File to write emitted context to (default stdout)
+        #[arg(short, long, requires = "emit")]
+        output: Option<String>,
+
+        /// Print a token-budgeted context pack of the best chunks, ready for a chat model
+        #[arg(long, default_value = "false", conflicts_with_all = ["verbose", "file_contents", "emit"])]
+        pack: bool,
+
+        /// Token budget for --pack
+        #[arg(long, default_value = "30000")]
+        max_tokens: usize,
+
+        /// How --pack renders files
+        #[arg(long, value_enum, default_value = "markdown")]
+        pack_format: pack::PackFormat,
+
+        /// Return functions, types and other symbol definitions instead of files
+        #[arg(long, default_value = "false", conflicts_with_all = ["emit", "pack"])]
+        symbols: bool,
+
+        /// Weight of BM25 matches on symbol names for --symbols
+        #[arg(long, default_value = "0.5", requires = "symbols")]
+        symbol_name_scale: f32,
+
+        #[command(flatten)]
+        ranking: RankingArgs,
+    },
+
+    /// Find the files relevant to a task and run aider once with them
+    Aider {
+        /// The task for aider, e.g. "make the background green"
+        #[arg(required = true)]
+        task: Vec<String>,
+
+        /// Number of top files to give aider
+        #[arg(short, long, default_value = "5")]
+        count: usize,
+
+        /// Only give aider files with similarity >= filter-similarity (0.0 to 1.0)
+        #[arg(short, long, default_value = "0.0")]
+        filter_similarity: f32,
+
+        /// Print the aider command instead of running it
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+
+        /// Show debug information including BM25 rankings
+        #[arg(long, default_value = "false")]
+        debug: bool,
+
+        /// The aider executable to run
+        #[arg(long, default_value = "aider")]
+        aider_bin: String,
+
+        #[command(flatten)]
+        ranking: RankingArgs,
+
+        /// Extra arguments passed through to aider (after `--`)
+        #[arg(last = true)]
+        aider_args: Vec<String>,
+    },
+
+    /// Measure retrieval quality on a dataset of queries and the files they should find
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Eval {
+        #[command(subcommand)]
+        action: Option<EvalCommands>,
+
+        /// JSONL file with lines like {"query": "...", "expected": ["src/a.rs", "src/b.rs:10-40"]}
+        #[arg(long, required = true)]
+        dataset: Option<String>,
+
+        /// Number of top files scored per query
+        #[arg(short = 'k', long = "k", default_value = "10")]
+        k: usize,
+
+        /// Also evaluate these settings (e.g. "--bm25-scale 0.3") and compare them side by side
+        #[arg(long, allow_hyphen_values = true)]
+        compare: Option<String>,
+
+        #[command(flatten)]
+        config: EvalConfigArgs,
+    },
+
+    /// Search ranking and chunking settings for the best scores on an eval dataset and
+    /// write them to the project config
+    Tune {
+        /// JSONL eval dataset, as for `eval`
+        #[arg(long, required = true)]
+        dataset: String,
+
+        /// Number of top files scored per query
+        #[arg(short = 'k', long = "k", default_value = "10")]
+        k: usize,
+
+        /// Metric to maximize
+        #[arg(long, value_enum, default_value = "ndcg")]
+        metric: eval::Metric,
+
+        /// Try this many random configurations instead of the full grid
+        #[arg(long)]
+        random: Option<usize>,
+
+        /// Seed for --random (default: current time)
+        #[arg(long, requires = "random")]
+        seed: Option<u64>,
+
+        /// Chunk sizes to try, comma separated (default: the index's); others are rescanned
+        #[arg(long, value_delimiter = ',')]
+        chunk_sizes: Vec<usize>,
+
+        /// Chunk overlaps to try, comma separated (default: the index's)
+        #[arg(long, value_delimiter = ',')]
+        chunk_overlaps: Vec<usize>,
+
+        /// Report the best configuration without writing the config
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+
+        /// Settings that are not tuned
+        #[command(flatten)]
+        config: EvalConfigArgs,
+    },
+
+    /// Look up indexed functions, types and other symbols by name
+    Symbols {
+        /// The symbol name, or part of it
+        query: String,
+
+        /// Only return symbols named exactly this (case-sensitive)
+        #[arg(long, default_value = "false")]
+        exact: bool,
+
+        /// Limit the number of results (0 for unlimited)
+        #[arg(short, long, default_value = "20")]
+        count: usize,
+
+        /// Show match scores as CSV
+        #[arg(long, default_value = "false")]
+        verbose: bool,
+
+        /// Show the line-numbered source of each symbol
+        #[arg(long, default_value = "false")]
+        file_contents: bool,
     },
 
     /// Expand a prompt using a system prompt
@@ -86,36 +557,276 @@ enum Commands {
         #[arg(long, required = false)]
         prompt: Option<String>,
     },
+
+    /// Carry out a coding task: edit the most relevant files with the chat model, show the
+    /// diff and apply it after confirmation
+    Edit {
+        /// The task (optional if using stdin)
+        task: Option<String>,
+
+        /// Number of most relevant files the model may change
+        #[arg(short = 'n', long, default_value = "5")]
+        files: usize,
+
+        /// Token budget for the files' contents
+        #[arg(long, default_value = "30000")]
+        max_tokens: usize,
+
+        /// Times edits that fail to apply are sent back to the model
+        #[arg(long, default_value = "1")]
+        retries: usize,
+
+        /// Apply the changes without asking
+        #[arg(short, long, default_value = "false")]
+        yes: bool,
+
+        /// With --yes, apply the edits that worked even when others failed
+        #[arg(long, default_value = "false")]
+        partial: bool,
+
+        #[command(flatten)]
+        config: EvalConfigArgs,
+    },
+
+    /// Answer a question about the codebase from its most relevant chunks, citing lines
+    Ask {
+        /// The question (optional if using stdin)
+        question: Option<String>,
+
+        /// Number of chunks to retrieve
+        #[arg(short = 'n', long, default_value = "10")]
+        chunks: usize,
+
+        /// Token budget for the retrieved context
+        #[arg(long, default_value = "12000")]
+        max_tokens: usize,
+
+        /// System prompt for answering (default asks for answers citing path:start-end)
+        #[arg(short, long)]
+        system_prompt: Option<String>,
+
+        /// Print the retrieved context sent with the question to stderr
+        #[arg(long, default_value = "false")]
+        show_context: bool,
+
+        #[command(flatten)]
+        config: EvalConfigArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum EvalCommands {
+    /// Write a dataset of questions the chat model asks about sampled chunks of the index
+    Generate {
+        /// JSONL file to write
+        #[arg(short, long, required = true)]
+        output: String,
+
+        /// Number of queries to generate
+        #[arg(short, long, default_value = "50")]
+        count: usize,
+
+        /// Questions to ask about each sampled chunk
+        #[arg(long, default_value = "1")]
+        per_chunk: usize,
+
+        /// Sample evenly across directories, extensions or both (e.g. dir,ext)
+        #[arg(long, value_enum, value_delimiter = ',')]
+        stratify: Vec<generate::Stratum>,
+
+        /// Skip questions whose embedding similarity to an earlier question is at least this
+        #[arg(long, default_value = "0.95")]
+        max_similarity: f32,
+
+        /// Seed for sampling chunks (default: current time)
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+}
+
+fn openai_api_key() -> String {
+    env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY not found in environment")
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
 
-    let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY not found in environment");
-
     let cli = Cli::parse();
 
-    match cli.command {
+    // Read once on first use and written once at the end, however many queries a command embeds
+    let embeddings =
+        cache::EmbeddingCache::load(cache::EMBEDDING_CACHE_FILE, cache::MAX_QUERY_EMBEDDINGS);
+    let result = run(cli.command, &embeddings).await;
+    if let Err(e) = embeddings.save() {
+        eprintln!("Error writing embedding cache: {}", e);
+    }
+    result
+}
+
+async fn run(
+    command: Commands,
+    embeddings: &cache::EmbeddingCache,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
         Commands::Scan {
             pattern,
             chunk_size,
             chunk_overlap,
             embed_metadata,
+            dry_run,
+            max_cost,
+            git,
+            rev,
+            stdin,
+            force,
         } => {
+            if stdin {
+                let mut buffer = String::new();
+                std::io::stdin().read_to_string(&mut buffer)?;
+                let paths: Vec<String> = buffer
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(update::normalize_path)
+                    .collect();
+                let mut store = update::load_store_for_update()?;
+                let (mut present, gone): (Vec<String>, Vec<String>) = paths
+                    .into_iter()
+                    .partition(|p| std::path::Path::new(p).is_file());
+                if !force {
+                    let unmatched: Vec<String> = update::unmatched_paths(&store, &present)
+                        .into_iter()
+                        .map(str::to_string)
+                        .collect();
+                    if !unmatched.is_empty() {
+                        println!(
+                            "Skipping {} files not matching \"{}\" (use --force to index them)",
+                            unmatched.len(),
+                            store.pattern
+                        );
+                        present.retain(|p| !unmatched.contains(p));
+                    }
+                }
+                let mut summary =
+                    update::add_files(&mut store, &present, &openai_api_key()).await?;
+                update::remove_files(&mut store, &gone, &mut summary);
+                if summary.changed() {
+                    scan::save_store(&store)?;
+                }
+                println!("{}", summary);
+                return Ok(());
+            }
+            // Required unless --stdin is given
+            let pattern = pattern.unwrap_or_default();
+            let config = config::load_config();
+            let chunk_size = chunk_size.or(config.chunk_size).unwrap_or(0);
+            let chunk_overlap = chunk_overlap.or(config.chunk_overlap).unwrap_or(0);
             if chunk_size > 0 && chunk_overlap >= chunk_size {
                 eprintln!("Error: chunk-overlap must be less than chunk-size");
                 std::process::exit(1);
             }
+            let source = match rev {
+                Some(rev) => scan::ScanSource::GitRev(rev),
+                None if git => scan::ScanSource::GitIndex,
+                None => scan::ScanSource::Walk,
+            };
+            if dry_run || max_cost.is_some() {
+                let estimate = estimate::estimate_scan(
+                    &pattern,
+                    chunk_size,
+                    chunk_overlap,
+                    embed_metadata,
+                    &source,
+                    &config,
+                )?;
+                if dry_run {
+                    estimate::print_estimate(&estimate, 10);
+                    return Ok(());
+                }
+                if let Some(max_cost) = max_cost {
+                    match estimate.cost() {
+                        Some(cost) if cost > max_cost => {
+                            eprintln!(
+                                "Error: estimated cost ${:.4} is over --max-cost ${:.4}",
+                                cost, max_cost
+                            );
+                            std::process::exit(1);
+                        }
+                        Some(_) => {}
+                        None => {
+                            eprintln!(
+                                "Error: no embedding price known for {}, cannot check --max-cost",
+                                estimate.model
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                }
+            }
             scan::scan_files(
                 &pattern,
-                &api_key,
+                &openai_api_key(),
                 chunk_size,
                 chunk_overlap,
                 embed_metadata,
+                &source,
             )
             .await?;
         }
+        Commands::Add { paths, force } => {
+            let paths: Vec<String> = paths.iter().map(|p| update::normalize_path(p)).collect();
+            let mut store = update::load_store_for_update()?;
+            let unmatched = update::unmatched_paths(&store, &paths);
+            if !force && !unmatched.is_empty() {
+                eprintln!(
+                    "Error: not matching the index's pattern \"{}\" (use --force to add anyway):",
+                    store.pattern
+                );
+                for path in unmatched {
+                    eprintln!("  {}", path);
+                }
+                std::process::exit(1);
+            }
+            let summary = update::add_files(&mut store, &paths, &openai_api_key()).await?;
+            if summary.changed() {
+                scan::save_store(&store)?;
+            }
+            println!("{}", summary);
+        }
+        Commands::Rm { paths } => {
+            let paths: Vec<String> = paths.iter().map(|p| update::normalize_path(p)).collect();
+            let mut store = update::load_store_for_update()?;
+            let mut summary = update::UpdateSummary::default();
+            for path in update::remove_files(&mut store, &paths, &mut summary) {
+                eprintln!("Not in the index: {}", path);
+            }
+            if summary.changed() {
+                scan::save_store(&store)?;
+            }
+            println!("{}", summary);
+        }
+        Commands::Info { top } => {
+            let store = search::load_store()?;
+            info::print_info(&info::index_info(&store, top));
+        }
+        Commands::Status => {
+            let store = search::load_store()?;
+            let changes = info::index_status(&store)?;
+            info::print_status(&store, &changes);
+        }
+        Commands::Serve { port, host, token } => {
+            // Fail on a bad config before serving, rather than on every request
+            serve_search_options(&[])?;
+            serve::serve(&host, port, token, openai_api_key(), serve_search_options).await?;
+        }
+        Commands::Mcp => {
+            serve_search_options(&[])?;
+            mcp::run(openai_api_key(), serve_search_options).await?;
+        }
+        Commands::Lsp => {
+            lsp::run(openai_api_key(), serve_search_options).await?;
+        }
         Commands::SuggestFiles {
             prompt,
             filter_similarity,
@@ -123,48 +834,392 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             debug,
             file_contents,
             count,
-            bm25_scale,
-            rag_scale,
+            emit,
+            edit_threshold,
+            output,
+            pack,
+            max_tokens,
+            pack_format,
+            symbols,
+            symbol_name_scale,
+            ranking,
         } => {
             if !(0.0..=1.0).contains(&filter_similarity) {
                 eprintln!("Error: filter-similarity must be between 0.0 and 1.0");
                 std::process::exit(1);
             }
-            let prompt_text = match prompt {
-                None => {
-                    // Only try to read from stdin if it's not a terminal
-                    if atty::isnt(atty::Stream::Stdin) {
-                        let mut buffer = String::new();
-                        std::io::stdin().read_to_string(&mut buffer)?;
-                        buffer.trim().to_string()
-                    } else {
-                        String::new()
+            let mut queries = prompt;
+            if queries.is_empty() {
+                // Only try to read from stdin if it's not a terminal
+                if atty::isnt(atty::Stream::Stdin) {
+                    let mut buffer = String::new();
+                    std::io::stdin().read_to_string(&mut buffer)?;
+                    if !buffer.trim().is_empty() {
+                        queries.push(search::WeightedQuery::new(buffer.trim()));
                     }
                 }
-                Some(p) => p,
-            };
+            }
 
-            if prompt_text.is_empty() {
+            if queries.is_empty() {
                 eprintln!("Error: No prompt given");
                 std::process::exit(1);
             }
 
-            if let Err(e) = search::find_related_files(
-                &prompt_text,
-                &api_key,
+            let options = search::SearchOptions {
                 filter_similarity,
                 verbose,
                 debug,
                 file_contents,
                 count,
-                bm25_scale,
-                rag_scale
-            )
-            .await
+                pack: pack.then_some(pack::PackOptions {
+                    max_tokens,
+                    format: pack_format,
+                }),
+                ..ranking.search_options()?
+            };
+
+            if symbols {
+                if let Err(e) = symbols::find_related_symbols(
+                    &queries,
+                    &openai_api_key(),
+                    &options,
+                    symbol_name_scale,
+                    embeddings,
+                )
+                .await
+                {
+                    eprintln!("Error finding related symbols: {}", e);
+                }
+            } else if let Some(format) = emit {
+                let store = search::load_store()?;
+                let matches =
+                    search::rank_files(&store, &queries, &openai_api_key(), &options, embeddings)
+                        .await?;
+                emit::emit_files(format, &matches, edit_threshold, output.as_deref())?;
+            } else if let Err(e) =
+                search::find_related_files(&queries, &openai_api_key(), &options, embeddings).await
             {
                 eprintln!("Error finding related files: {}", e);
             }
         }
+        Commands::Aider {
+            task,
+            count,
+            filter_similarity,
+            dry_run,
+            debug,
+            aider_bin,
+            ranking,
+            aider_args,
+        } => {
+            if !(0.0..=1.0).contains(&filter_similarity) {
+                eprintln!("Error: filter-similarity must be between 0.0 and 1.0");
+                std::process::exit(1);
+            }
+            let task = task.join(" ");
+            let options = search::SearchOptions {
+                filter_similarity,
+                debug,
+                count,
+                ..ranking.search_options()?
+            };
+
+            let store = search::load_store()?;
+            let files: Vec<String> = search::rank_files(
+                &store,
+                &[search::WeightedQuery::new(&task)],
+                &openai_api_key(),
+                &options,
+                embeddings,
+            )
+            .await?
+            .into_iter()
+            .map(|m| m.filename)
+            .collect();
+            if files.is_empty() {
+                eprintln!("Error: No relevant files found for the task");
+                std::process::exit(1);
+            }
+
+            let args = aider::aider_args(&task, &files, &aider_args);
+            if dry_run {
+                println!("{}", aider::format_command(&aider_bin, &args));
+                return Ok(());
+            }
+            // Exiting skips the save at the end of main
+            embeddings.save()?;
+            std::process::exit(aider::run_aider(&aider_bin, &args)?);
+        }
+        Commands::Eval {
+            action:
+                Some(EvalCommands::Generate {
+                    output,
+                    count,
+                    per_chunk,
+                    stratify,
+                    max_similarity,
+                    seed,
+                }),
+            ..
+        } => {
+            let store = search::load_store()?;
+            let seed = seed.unwrap_or_else(random::time_seed);
+            println!("Sampling chunks with seed {}", seed);
+            let mut file = std::fs::File::create(&output)?;
+            let dataset = generate::generate_dataset(
+                &store,
+                &openai_api_key(),
+                &generate::GenerateOptions {
+                    count,
+                    per_chunk: per_chunk.max(1),
+                    strata: stratify,
+                    max_similarity,
+                    seed,
+                },
+                &mut file,
+                embeddings,
+            )
+            .await?;
+            if dataset.len() < count {
+                println!(
+                    "Only {} of {} queries could be generated from the index",
+                    dataset.len(),
+                    count
+                );
+            }
+            println!("Wrote {} queries to {}", dataset.len(), output);
+        }
+        Commands::Eval {
+            dataset,
+            k,
+            compare,
+            config,
+            ..
+        } => {
+            // Required unless a subcommand is given
+            let dataset = dataset.unwrap_or_default();
+            // Parse the second configuration before spending anything on the first
+            let compare = compare.map(|flags| {
+                CompareArgs::try_parse_from(eval::split_args(&flags)).unwrap_or_else(|e| e.exit())
+            });
+            let store = search::load_store()?;
+            let dataset = eval::load_dataset(&dataset)?;
+            if dataset.is_empty() {
+                eprintln!("Error: the dataset has no queries");
+                std::process::exit(1);
+            }
+            let api_key = openai_api_key();
+
+            let baseline = eval::evaluate(
+                &store,
+                &dataset,
+                &api_key,
+                &config.search_options()?,
+                k,
+                embeddings,
+            )
+            .await?;
+            match compare {
+                Some(compare) => {
+                    let candidate = eval::evaluate(
+                        &store,
+                        &dataset,
+                        &api_key,
+                        &compare.config.search_options()?,
+                        k,
+                        embeddings,
+                    )
+                    .await?;
+                    eval::print_comparison(&dataset, &baseline, &candidate, k);
+                }
+                None => eval::print_report(&dataset, &baseline, k),
+            }
+        }
+        Commands::Tune {
+            dataset,
+            k,
+            metric,
+            random,
+            seed,
+            mut chunk_sizes,
+            mut chunk_overlaps,
+            dry_run,
+            config,
+        } => {
+            let store = search::load_store()?;
+            let dataset = eval::load_dataset(&dataset)?;
+            if dataset.is_empty() {
+                eprintln!("Error: the dataset has no queries");
+                std::process::exit(1);
+            }
+            if chunk_sizes.is_empty() {
+                chunk_sizes.push(store.chunk_size);
+            }
+            if chunk_overlaps.is_empty() {
+                chunk_overlaps.push(store.overlap_size);
+            }
+            // Overlap only applies to chunked files, and must be smaller than the chunk
+            let mut chunkings = Vec::new();
+            for &size in &chunk_sizes {
+                for &overlap in &chunk_overlaps {
+                    let overlap = if size == 0 { 0 } else { overlap };
+                    if (size == 0 || overlap < size) && !chunkings.contains(&(size, overlap)) {
+                        chunkings.push((size, overlap));
+                    }
+                }
+            }
+            if chunkings.is_empty() {
+                eprintln!("Error: every chunk overlap is at least the chunk size");
+                std::process::exit(1);
+            }
+
+            let configs = match random {
+                Some(count) => {
+                    let seed = seed.unwrap_or_else(random::time_seed);
+                    println!("Random search with seed {}", seed);
+                    tune::random(&chunkings, count, seed)
+                }
+                None => tune::grid(&chunkings),
+            };
+            println!(
+                "Evaluating {} configurations on {} queries",
+                configs.len(),
+                dataset.len()
+            );
+
+            let api_key = openai_api_key();
+            let results = tune::tune(
+                &store,
+                &dataset,
+                &api_key,
+                &config.search_options()?,
+                &configs,
+                tune::TuneTarget { metric, k },
+                embeddings,
+            )
+            .await?;
+            tune::print_best(&results, k);
+
+            let Some((best, _)) = results.first() else {
+                return Ok(());
+            };
+            if dry_run {
+                println!("\nDry run, not writing {}", config::CONFIG_FILE);
+            } else {
+                tune::write_config(best)?;
+                println!("\nWrote the best configuration to {}", config::CONFIG_FILE);
+            }
+            if (best.chunk_size, best.chunk_overlap) != (store.chunk_size, store.overlap_size) {
+                println!(
+                    "The best chunking differs from the index's, rescan to use it: luckyshot scan --pattern \"{}\" --chunk-size {} --chunk-overlap {}",
+                    store.pattern, best.chunk_size, best.chunk_overlap
+                );
+            }
+        }
+        Commands::Symbols {
+            query,
+            exact,
+            count,
+            verbose,
+            file_contents,
+        } => {
+            let store = search::load_store()?;
+            if store.symbols.is_empty() {
+                eprintln!("No symbols in the index, rescan first");
+                std::process::exit(1);
+            }
+            let mut matches = symbols::lookup(&store, &query, exact);
+            if count > 0 {
+                matches.truncate(count);
+            }
+            symbols::print_symbol_matches(&store, &matches, verbose, file_contents);
+        }
+        Commands::Edit {
+            task,
+            files,
+            max_tokens,
+            retries,
+            yes,
+            partial,
+            config,
+        } => {
+            let task = match task {
+                Some(task) => task,
+                // Only try to read from stdin if it's not a terminal
+                None if atty::isnt(atty::Stream::Stdin) => {
+                    let mut buffer = String::new();
+                    std::io::stdin().read_to_string(&mut buffer)?;
+                    buffer
+                }
+                None => String::new(),
+            };
+            if task.trim().is_empty() {
+                eprintln!("Error: No task given");
+                std::process::exit(1);
+            }
+            let store = search::load_store()?;
+            // Rank every chunk, the best distinct files are taken from them
+            let search_options = search::SearchOptions {
+                count: 0,
+                ..config.search_options()?
+            };
+            edit::edit(
+                &store,
+                task.trim(),
+                &openai_api_key(),
+                &search_options,
+                &edit::EditOptions {
+                    files,
+                    max_tokens,
+                    retries,
+                    yes,
+                    partial,
+                },
+                embeddings,
+            )
+            .await?;
+        }
+        Commands::Ask {
+            question,
+            chunks,
+            max_tokens,
+            system_prompt,
+            show_context,
+            config,
+        } => {
+            let question = match question {
+                Some(question) => question,
+                // Only try to read from stdin if it's not a terminal
+                None if atty::isnt(atty::Stream::Stdin) => {
+                    let mut buffer = String::new();
+                    std::io::stdin().read_to_string(&mut buffer)?;
+                    buffer
+                }
+                None => String::new(),
+            };
+            if question.trim().is_empty() {
+                eprintln!("Error: No question given");
+                std::process::exit(1);
+            }
+            let store = search::load_store()?;
+            let options = search::SearchOptions {
+                count: chunks,
+                ..config.search_options()?
+            };
+            ask::ask(
+                &store,
+                question.trim(),
+                &openai_api_key(),
+                &options,
+                &ask::AskOptions {
+                    max_tokens,
+                    system_prompt: system_prompt.unwrap_or_else(|| ask::ASK_SYSTEM_PROMPT.into()),
+                    show_context,
+                },
+                embeddings,
+            )
+            .await?;
+        }
         Commands::Expand {
             prompt,
             system_prompt,
@@ -174,7 +1229,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     // Only try to read from stdin if it's not a terminal
                     if atty::isnt(atty::Stream::Stdin) {
                         let mut buffer = String::new();
-                        std::io::stdin().read_line(&mut buffer)?;
+                        std::io::stdin().read_to_string(&mut buffer)?;
                         buffer
                     } else {
                         String::new()
@@ -189,8 +1244,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
 
             if !prompt_text.trim().is_empty() {
-                match openai::get_openai_chat_completion(&prompt_text, &system_prompt, &api_key)
-                    .await
+                match openai::get_openai_chat_completion(
+                    &prompt_text,
+                    &system_prompt,
+                    &openai_api_key(),
+                )
+                .await
                 {
                     Ok(expanded) => println!("{}", expanded),
                     Err(e) => eprintln!("Error expanding prompt: {}", e),
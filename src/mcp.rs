@@ -0,0 +1,345 @@
+use crate::cache::{EmbeddingCache, EMBEDDING_CACHE_FILE, MAX_QUERY_EMBEDDINGS};
+use crate::serve::{LoadedIndex, OptionsParser};
+use glob_match::glob_match;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+// Newest first, the first is offered to clients asking for one we don't know
+const PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl std::fmt::Display) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchFilesArgs {
+    query: String,
+    #[serde(default)]
+    path_glob: Option<String>,
+    #[serde(flatten)]
+    knobs: serde_json::Map<String, Value>, // Ranking options, as for `serve`
+}
+
+#[derive(Deserialize)]
+struct ReadChunkArgs {
+    path: String,
+    #[serde(default)]
+    start_line: Option<usize>,
+    #[serde(default)]
+    end_line: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct RescanPathsArgs {
+    paths: Vec<String>,
+    #[serde(default)]
+    force: bool,
+}
+
+fn tools() -> Value {
+    json!([
+        {
+            "name": "search_files",
+            "description": "Find the files and line spans of this codebase most relevant to a \
+                question or topic, using hybrid semantic and keyword search over the luckyshot \
+                index. Returns scored results, best first, with the score components.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "query": { "type": "string", "description": "What to look for, in natural language or identifiers" },
+                    "count": { "type": "integer", "minimum": 0, "description": "Number of results (default 10, 0 for all)" },
+                    "path_glob": { "type": "string", "description": "Only return files matching this glob, e.g. src/**/*.rs" },
+                    "filter_similarity": { "type": "number", "minimum": 0, "maximum": 1, "description": "Only return results scoring at least this" },
+                    "bm25_scale": { "type": "number", "description": "Weight of keyword (BM25) scores" },
+                    "rag_scale": { "type": "number", "description": "Weight of embedding scores" },
+                    "fusion": { "type": "string", "enum": ["weighted", "rrf"] },
+                    "aggregation": { "type": "string", "enum": ["max", "mean", "sum"] },
+                    "prefer": { "type": "array", "items": { "type": "string" }, "description": "Score multipliers by extension or path glob, e.g. rs=1.2 or path:src/**=1.1" },
+                    "boost_regex": { "type": "array", "items": { "type": "string" }, "description": "Boost files by regex match count, as <regex> or <regex>=<weight>" },
+                    "not": { "type": "array", "items": { "type": "string" }, "description": "Demote results similar to these queries" },
+                    "recency_scale": { "type": "number", "description": "Weight of recently modified files" },
+                    "rerank": { "type": "integer", "minimum": 0, "description": "Rerank this many top results with the chat model" },
+                    "hyde": { "type": "boolean", "description": "Search with a hypothetical code snippet answering the query" },
+                    "expand_deps": { "type": "integer", "minimum": 0, "description": "Add up to this many imported or importing files" },
+                    "expand_cochange": { "type": "integer", "minimum": 0, "description": "Add up to this many files often committed together with the results" }
+                },
+                "required": ["query"]
+            }
+        },
+        {
+            "name": "read_chunk",
+            "description": "Read line-numbered lines of an indexed file, e.g. the span of a \
+                search_files result. Without lines, returns the whole file.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "File path as returned by search_files" },
+                    "start_line": { "type": "integer", "minimum": 1, "description": "First line, 1-based" },
+                    "end_line": { "type": "integer", "minimum": 1, "description": "Last line, inclusive" }
+                },
+                "required": ["path"],
+                "additionalProperties": false
+            }
+        },
+        {
+            "name": "index_status",
+            "description": "Describe the luckyshot index and list the files that are new, \
+                modified or deleted since it was scanned.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {},
+                "additionalProperties": false
+            }
+        },
+        {
+            "name": "rescan_paths",
+            "description": "Re-index files after editing them, so search_files sees their \
+                current contents. Paths that no longer exist are removed from the index.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "paths": { "type": "array", "items": { "type": "string" }, "minItems": 1 },
+                    "force": { "type": "boolean", "description": "Also index files not matching the index's pattern" }
+                },
+                "required": ["paths"],
+                "additionalProperties": false
+            }
+        }
+    ])
+}
+
+async fn search_files(
+    index: &LoadedIndex,
+    arguments: Value,
+    api_key: &str,
+    parse_options: OptionsParser,
+    embeddings: &EmbeddingCache,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let args: SearchFilesArgs = serde_json::from_value(arguments)?;
+    let mut options = crate::serve::knob_args(&args.knobs).and_then(|a| parse_options(&a))?;
+    // Filter by path before counting
+    let count = options.count;
+    if args.path_glob.is_some() {
+        options.count = 0;
+    }
+
+    let queries = [crate::search::WeightedQuery::new(&args.query)];
+    let ranked =
+        crate::search::rank_files(&index.store, &queries, api_key, &options, embeddings).await;
+    // Only writes the cache when a new query was embedded
+    if let Err(e) = embeddings.save() {
+        eprintln!("Error writing embedding cache: {}", e);
+    }
+    let mut matches = ranked?;
+    if let Some(glob) = &args.path_glob {
+        matches.retain(|m| glob_match(glob, &m.filename));
+        if count > 0 {
+            matches.truncate(count);
+        }
+    }
+    let results = crate::serve::search_results(index, &matches);
+    Ok(serde_json::to_string_pretty(&results)?)
+}
+
+fn read_chunk(index: &LoadedIndex, arguments: Value) -> Result<String, Box<dyn std::error::Error>> {
+    let args: ReadChunkArgs = serde_json::from_value(arguments)?;
+    let path = crate::update::normalize_path(&args.path);
+    // Only hand out what was indexed, not any file the process can read
+    if !index.store.bm25_files.iter().any(|f| f.filename == path) {
+        return Err(format!("{} is not in the index", path).into());
+    }
+    let contents = std::fs::read_to_string(&path)?;
+    let line_count = contents.lines().count();
+    let start_line = args.start_line.unwrap_or(1).max(1);
+    let end_line = args.end_line.unwrap_or(line_count).min(line_count);
+    if start_line > end_line {
+        return Err(format!("{} has {} lines", path, line_count).into());
+    }
+    let span = crate::span::LineSpan {
+        start_line,
+        start_column: 1,
+        end_line,
+        end_column: 1,
+    };
+    Ok(format!(
+        "{}:{}\n{}",
+        path,
+        span,
+        crate::span::numbered_lines(&contents, span)
+    ))
+}
+
+fn index_status(index: &LoadedIndex) -> Result<String, Box<dyn std::error::Error>> {
+    let store = &index.store;
+    let changes: Vec<Value> = crate::info::index_status(store)?
+        .iter()
+        .map(|(status, filename)| json!({ "status": status, "filename": filename }))
+        .collect();
+    let status = json!({
+        "pattern": store.pattern,
+        "files": store.bm25_files.len(),
+        "chunks": store.rag_vectors.len(),
+        "scanned": crate::recency::format_timestamp(store.date),
+        "git_rev": store.git_rev,
+        "changes": changes,
+        "rescan_command": crate::info::rescan_command(store),
+    });
+    Ok(serde_json::to_string_pretty(&status)?)
+}
+
+fn rescan_paths(
+    index: &mut LoadedIndex,
+    arguments: Value,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let args: RescanPathsArgs = serde_json::from_value(arguments)?;
+    let summary = crate::update::rescan_in_child(&args.paths, args.force)?;
+    index.reload();
+    Ok(summary)
+}
+
+async fn call_tool(
+    index: &mut LoadedIndex,
+    params: &Value,
+    api_key: &str,
+    parse_options: OptionsParser,
+    embeddings: &EmbeddingCache,
+) -> Result<Value, RpcError> {
+    let name = params["name"].as_str().unwrap_or_default();
+    let arguments = params.get("arguments").cloned().unwrap_or(json!({}));
+    if index.is_stale() {
+        index.reload();
+    }
+
+    let started = Instant::now();
+    let result = match name {
+        "search_files" => search_files(index, arguments, api_key, parse_options, embeddings).await,
+        "read_chunk" => read_chunk(index, arguments),
+        "index_status" => index_status(index),
+        "rescan_paths" => rescan_paths(index, arguments),
+        _ => {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                format!("Unknown tool: {}", name),
+            ))
+        }
+    };
+    eprintln!("{} {}ms", name, started.elapsed().as_millis());
+
+    // Tool failures go back to the model as results it can act on
+    let (text, is_error) = match result {
+        Ok(text) => (text, false),
+        Err(e) => (e.to_string(), true),
+    };
+    Ok(json!({
+        "content": [{ "type": "text", "text": text }],
+        "isError": is_error,
+    }))
+}
+
+async fn handle(
+    index: &mut LoadedIndex,
+    method: &str,
+    params: &Value,
+    api_key: &str,
+    parse_options: OptionsParser,
+    embeddings: &EmbeddingCache,
+) -> Result<Value, RpcError> {
+    match method {
+        "initialize" => {
+            let requested = params["protocolVersion"].as_str().unwrap_or_default();
+            let version = PROTOCOL_VERSIONS
+                .iter()
+                .find(|v| **v == requested)
+                .unwrap_or(&PROTOCOL_VERSIONS[0]);
+            Ok(json!({
+                "protocolVersion": version,
+                "capabilities": { "tools": {} },
+                "serverInfo": { "name": "luckyshot", "version": env!("CARGO_PKG_VERSION") },
+            }))
+        }
+        "ping" => Ok(json!({})),
+        "tools/list" => Ok(json!({ "tools": tools() })),
+        "tools/call" => call_tool(index, params, api_key, parse_options, embeddings).await,
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method: {}", method),
+        )),
+    }
+}
+
+fn send(message: &Value) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "{}", message)?;
+    stdout.flush()
+}
+
+/// Serve the index to an MCP client over stdio, one JSON-RPC message per line, until
+/// stdin closes. Logs go to stderr.
+pub async fn run(
+    api_key: String,
+    parse_options: OptionsParser,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut index = LoadedIndex::load()?;
+    let embeddings = EmbeddingCache::load(EMBEDDING_CACHE_FILE, MAX_QUERY_EMBEDDINGS);
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message: Value = match serde_json::from_str(&line) {
+            Ok(message) => message,
+            Err(e) => {
+                send(&json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": { "code": PARSE_ERROR, "message": e.to_string() },
+                }))?;
+                continue;
+            }
+        };
+        // Notifications (initialized, cancelled) need no answer
+        let Some(id) = message.get("id").cloned() else {
+            continue;
+        };
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = message.get("params").cloned().unwrap_or(json!({}));
+
+        let response = match handle(
+            &mut index,
+            method,
+            &params,
+            &api_key,
+            parse_options,
+            &embeddings,
+        )
+        .await
+        {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": e.code, "message": e.message },
+            }),
+        };
+        send(&response)?;
+    }
+    Ok(())
+}
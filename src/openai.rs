@@ -3,16 +3,33 @@ use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::vec::Vec;
 
+pub const CHAT_MODEL: &str = "gpt-4o-mini";
+pub const EMBEDDING_MODEL: &str = "text-embedding-ada-002";
+
 #[derive(Debug, Serialize)]
 struct OpenAIChatRequest {
     model: String,
     messages: Vec<ChatMessage>,
     temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize)]
 struct OpenAIChatResponse {
     choices: Vec<OpenAIChatChoice>,
+    #[serde(default)]
+    usage: Option<ChatUsage>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy)]
+pub struct ChatUsage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
 }
 
 #[derive(Debug, Deserialize)]
@@ -20,6 +37,27 @@ struct OpenAIChatChoice {
     message: ChatMessage,
 }
 
+#[derive(Debug, Deserialize)]
+struct OpenAIChatChunk {
+    #[serde(default)]
+    choices: Vec<OpenAIChatChunkChoice>,
+    #[serde(default)]
+    usage: Option<ChatUsage>,
+    #[serde(default)]
+    error: Option<serde_json::Value>, // Sent instead of a chunk when generation fails
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIChatChunkChoice {
+    delta: ChatDelta,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatDelta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ChatMessage {
     role: String,
@@ -45,7 +83,7 @@ struct EmbeddingData {
 pub async fn get_openai_chat_completion(prompt: &str, system_prompt: &str, api_key: &str) -> Result<String, Box<dyn Error>> {
     let client = Client::new();
     let request = OpenAIChatRequest {
-        model: "gpt-4o-mini".to_string(),
+        model: CHAT_MODEL.to_string(),
         messages: vec![
             ChatMessage {
                 role: "system".to_string(),
@@ -57,6 +95,9 @@ pub async fn get_openai_chat_completion(prompt: &str, system_prompt: &str, api_k
             },
         ],
         temperature: 0.7,
+        response_format: None,
+        stream: false,
+        stream_options: None,
     };
 
     let response = client
@@ -71,11 +112,138 @@ pub async fn get_openai_chat_completion(prompt: &str, system_prompt: &str, api_k
     Ok(chat_response.choices[0].message.content.clone())
 }
 
+/// Chat completion constrained to a JSON schema, returning the JSON text and token usage
+pub async fn get_openai_json_completion(
+    prompt: &str,
+    system_prompt: &str,
+    schema_name: &str,
+    schema: serde_json::Value,
+    api_key: &str,
+) -> Result<(String, ChatUsage), Box<dyn Error>> {
+    let client = Client::new();
+    let request = OpenAIChatRequest {
+        model: CHAT_MODEL.to_string(),
+        messages: vec![
+            ChatMessage {
+                role: "system".to_string(),
+                content: system_prompt.to_string(),
+            },
+            ChatMessage {
+                role: "user".to_string(),
+                content: prompt.to_string(),
+            },
+        ],
+        temperature: 0.0,
+        response_format: Some(serde_json::json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": schema_name,
+                "strict": true,
+                "schema": schema,
+            }
+        })),
+        stream: false,
+        stream_options: None,
+    };
+
+    let response = client
+        .post("https://api.openai.com/v1/chat/completions")
+        .header("Authorization", format!("Bearer {}", api_key))
+        .header("Content-Type", "application/json")
+        .json(&request)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let chat_response: OpenAIChatResponse = response.json().await?;
+    let choice = chat_response
+        .choices
+        .first()
+        .ok_or("No choices in chat response")?;
+    Ok((
+        choice.message.content.clone(),
+        chat_response.usage.unwrap_or_default(),
+    ))
+}
+
+/// Chat completion streamed as server-sent events, passing each piece of the answer to
+/// `on_delta` as it arrives. Returns the whole answer and token usage.
+pub async fn stream_openai_chat_completion(
+    prompt: &str,
+    system_prompt: &str,
+    api_key: &str,
+    mut on_delta: impl FnMut(&str),
+) -> Result<(String, ChatUsage), Box<dyn Error>> {
+    let client = Client::new();
+    let request = OpenAIChatRequest {
+        model: CHAT_MODEL.to_string(),
+        messages: vec![
+            ChatMessage {
+                role: "system".to_string(),
+                content: system_prompt.to_string(),
+            },
+            ChatMessage {
+                role: "user".to_string(),
+                content: prompt.to_string(),
+            },
+        ],
+        temperature: 0.2,
+        response_format: None,
+        stream: true,
+        stream_options: Some(serde_json::json!({ "include_usage": true })),
+    };
+
+    let mut response = client
+        .post("https://api.openai.com/v1/chat/completions")
+        .header("Authorization", format!("Bearer {}", api_key))
+        .header("Content-Type", "application/json")
+        .json(&request)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let mut answer = String::new();
+    let mut usage = ChatUsage::default();
+    let mut pending: Vec<u8> = Vec::new();
+    while let Some(bytes) = response.chunk().await? {
+        pending.extend_from_slice(&bytes);
+        // Events are lines of `data: <json>`, the last one `data: [DONE]`
+        while let Some(newline) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                return Ok((answer, usage));
+            }
+            let chunk: OpenAIChatChunk = serde_json::from_str(data)?;
+            if let Some(error) = chunk.error {
+                let message = error["message"]
+                    .as_str()
+                    .map_or_else(|| error.to_string(), str::to_string);
+                return Err(format!("Chat completion failed mid-stream: {}", message).into());
+            }
+            if let Some(chunk_usage) = chunk.usage {
+                usage = chunk_usage;
+            }
+            for choice in chunk.choices {
+                if let Some(content) = choice.delta.content {
+                    on_delta(&content);
+                    answer.push_str(&content);
+                }
+            }
+        }
+    }
+    Err("Chat completion stream ended before completion".into())
+}
+
 pub async fn get_embedding(text: &str, api_key: &str) -> Result<Vec<f32>, Box<dyn Error>> {
     let client = Client::new();
     let request = EmbeddingRequest {
         input: text.to_string(),
-        model: "text-embedding-ada-002".to_string(),
+        model: EMBEDDING_MODEL.to_string(),
     };
 
     let response = client
@@ -0,0 +1,298 @@
+use crate::scan::FileVectorStore;
+use crate::search::FileMatch;
+use crate::token_count::count_tokens;
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum PackFormat {
+    /// Markdown fenced code blocks
+    Markdown,
+    /// `<file path=...>` tags
+    Xml,
+}
+
+#[derive(Clone)]
+pub struct PackOptions {
+    pub max_tokens: usize,
+    pub format: PackFormat,
+}
+
+/// A rendered pack, the files that didn't (fully) fit, and the line ranges included
+pub struct Pack {
+    pub text: String,
+    pub skipped: Vec<String>,
+    pub ranges: Vec<(String, usize, usize)>, // Filename and 1-based, inclusive lines
+}
+
+struct PackedFile {
+    filename: String,
+    contents: String,
+    lines: Vec<String>,
+    ranges: Vec<(usize, usize)>, // Merged half-open line ranges, 0-based
+    tokens: usize,
+    lines_left_out: usize, // Lines of matched chunks that didn't fit, counting overlaps
+}
+
+fn fence_language(filename: &str) -> &str {
+    let extension = Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("");
+    match extension {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" | "tsx" => "typescript",
+        "md" => "markdown",
+        "sh" => "bash",
+        "yml" => "yaml",
+        other => other,
+    }
+}
+
+fn escape_xml_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+}
+
+/// Lines `start..end` (0-based, half-open) of a file, headed by its path and lines
+pub fn render_range(
+    format: PackFormat,
+    filename: &str,
+    start: usize,
+    end: usize,
+    text: &str,
+) -> String {
+    // Line numbers are 1-based and inclusive for display
+    let (first, last) = (start + 1, end);
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    match format {
+        PackFormat::Markdown => {
+            // Use a longer fence than any backtick run in the text
+            let longest_run = text
+                .split(|c| c != '`')
+                .map(|run| run.len())
+                .max()
+                .unwrap_or(0);
+            let fence = "`".repeat(longest_run.max(2) + 1);
+            format!(
+                "## {}:{}-{}\n\n{}{}\n{}\n{}\n\n",
+                filename,
+                first,
+                last,
+                fence,
+                fence_language(filename),
+                text,
+                fence
+            )
+        }
+        PackFormat::Xml => format!(
+            "<file path=\"{}\" lines=\"{}-{}\">\n{}\n</file>\n\n",
+            escape_xml_attribute(filename),
+            first,
+            last,
+            text
+        ),
+    }
+}
+
+/// Indented tree of the packed file paths
+fn render_tree(filenames: &[&str]) -> String {
+    let mut sorted: Vec<&str> = filenames.to_vec();
+    sorted.sort();
+
+    let mut tree = String::new();
+    let mut previous: Vec<&str> = Vec::new();
+    for filename in sorted {
+        let components: Vec<&str> = filename.split('/').collect();
+        let (directories, name) = components.split_at(components.len() - 1);
+        let shared = directories
+            .iter()
+            .zip(previous.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        for (depth, directory) in directories.iter().enumerate().skip(shared) {
+            tree.push_str(&format!("{}{}/\n", "  ".repeat(depth), directory));
+        }
+        tree.push_str(&format!("{}{}\n", "  ".repeat(directories.len()), name[0]));
+        previous = directories.to_vec();
+    }
+    tree
+}
+
+fn render_header(format: PackFormat, tree: &str) -> String {
+    match format {
+        PackFormat::Markdown => format!("# Files\n\n```\n{}```\n\n", tree),
+        PackFormat::Xml => format!("<file_tree>\n{}</file_tree>\n\n", tree),
+    }
+}
+
+impl PackedFile {
+    fn range_text(&self, start: usize, end: usize) -> String {
+        self.lines[start..end].concat()
+    }
+
+    fn ranges_tokens(&self, ranges: &[(usize, usize)], format: PackFormat) -> usize {
+        ranges
+            .iter()
+            .map(|&(start, end)| {
+                count_tokens(&render_range(
+                    format,
+                    &self.filename,
+                    start,
+                    end,
+                    &self.range_text(start, end),
+                ))
+            })
+            .sum()
+    }
+}
+
+fn merge_range(ranges: &[(usize, usize)], range: (usize, usize)) -> Vec<(usize, usize)> {
+    let mut all: Vec<(usize, usize)> = ranges.to_vec();
+    all.push(range);
+    all.sort();
+
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in all {
+        match merged.last_mut() {
+            // Touching ranges are merged too, so the excerpt reads continuously
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Fill the token budget greedily in score order, merging overlapping chunks of the
+/// same file and trimming the last chunk that fits partially to whole lines. Returns
+/// the rendered pack with what was left out and what went in.
+pub fn pack(store: &FileVectorStore, matches: &[FileMatch], options: &PackOptions) -> Pack {
+    let format = options.format;
+    let mut files: Vec<PackedFile> = Vec::new();
+    let mut file_indices: HashMap<String, usize> = HashMap::new();
+    // Files in the tree so far, and the tokens of the header showing them
+    let mut tree_filenames: Vec<String> = Vec::new();
+    let mut header_tokens = count_tokens(&render_header(format, ""));
+    let mut used = header_tokens;
+
+    for m in matches {
+        let chunk = &store.rag_vectors[m.chunk_index];
+
+        let file_index = match file_indices.get(&chunk.filename) {
+            Some(&index) => index,
+            None => {
+                let Ok(contents) = std::fs::read_to_string(&chunk.filename) else {
+                    continue;
+                };
+                files.push(PackedFile {
+                    filename: chunk.filename.clone(),
+                    lines: contents.split_inclusive('\n').map(String::from).collect(),
+                    contents,
+                    ranges: Vec::new(),
+                    tokens: 0,
+                    lines_left_out: 0,
+                });
+                file_indices.insert(chunk.filename.clone(), files.len() - 1);
+                files.len() - 1
+            }
+        };
+        let file = &files[file_index];
+
+        // A chunk that can't be found in the changed file is left out
+        let Some(span) = crate::span::current_span(store, chunk, &file.contents) else {
+            continue;
+        };
+        let (start, end) = (span.start_line - 1, span.end_line.min(file.lines.len()));
+        if start >= end {
+            continue;
+        }
+        // A new file also grows the tree, by its line and any new directory lines
+        let tree_tokens = if file.ranges.is_empty() {
+            let mut filenames: Vec<&str> = tree_filenames.iter().map(|f| f.as_str()).collect();
+            filenames.push(&file.filename);
+            count_tokens(&render_header(format, &render_tree(&filenames)))
+                .saturating_sub(header_tokens)
+        } else {
+            0
+        };
+
+        // Take the longest prefix of the chunk's lines that fits the budget
+        let mut fitted = None;
+        let mut end_line = end;
+        while end_line > start {
+            let ranges = merge_range(&file.ranges, (start, end_line));
+            let tokens = file.ranges_tokens(&ranges, format);
+            let delta = tokens.saturating_sub(file.tokens) + tree_tokens;
+            if used + delta <= options.max_tokens {
+                fitted = Some((ranges, tokens, delta));
+                break;
+            }
+            // Shrink quickly for large chunks, then line by line
+            end_line = if end_line - start > 64 {
+                start + (end_line - start) / 2
+            } else {
+                end_line - 1
+            };
+        }
+
+        match fitted {
+            Some((ranges, tokens, delta)) => {
+                let file = &mut files[file_index];
+                if file.ranges.is_empty() {
+                    tree_filenames.push(file.filename.clone());
+                    header_tokens += tree_tokens;
+                }
+                if end_line < end {
+                    file.lines_left_out += end - end_line;
+                }
+                file.ranges = ranges;
+                file.tokens = tokens;
+                used += delta;
+            }
+            None => files[file_index].lines_left_out += end - start,
+        }
+    }
+
+    let skipped = files
+        .iter()
+        .filter(|f| f.lines_left_out > 0)
+        .map(|f| {
+            if f.ranges.is_empty() {
+                format!("{} (not included)", f.filename)
+            } else {
+                format!(
+                    "{} (partially included, {} chunk lines left out)",
+                    f.filename, f.lines_left_out
+                )
+            }
+        })
+        .collect();
+
+    let packed: Vec<&PackedFile> = files.iter().filter(|f| !f.ranges.is_empty()).collect();
+    let filenames: Vec<&str> = packed.iter().map(|f| f.filename.as_str()).collect();
+    let mut output = render_header(format, &render_tree(&filenames));
+    let mut ranges = Vec::new();
+    for file in packed {
+        for &(start, end) in &file.ranges {
+            output.push_str(&render_range(
+                format,
+                &file.filename,
+                start,
+                end,
+                &file.range_text(start, end),
+            ));
+            ranges.push((file.filename.clone(), start + 1, end));
+        }
+    }
+
+    Pack {
+        text: output,
+        skipped,
+        ranges,
+    }
+}
@@ -0,0 +1,82 @@
+use glob_match::glob_match;
+use std::path::Path;
+
+// Matched against paths relative to the scan directory
+const TEST_PATTERNS: &[&str] = &[
+    "**/tests/**",
+    "**/test/**",
+    "**/*_test.*",
+    "**/test_*.*",
+    "**/*.test.*",
+    "**/*.spec.*",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PreferenceTarget {
+    Extension(String),
+    Path(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Preference {
+    pub target: PreferenceTarget,
+    pub weight: f32,
+}
+
+/// Parse `<extension>=<weight>` or `path:<glob>=<weight>`
+pub fn parse_preference(value: &str) -> Result<Preference, String> {
+    let (target, weight) = value.rsplit_once('=').ok_or_else(|| {
+        format!(
+            "expected <extension>=<weight> or path:<glob>=<weight>, got {}",
+            value
+        )
+    })?;
+    let weight = weight
+        .parse::<f32>()
+        .map_err(|_| format!("invalid weight in {}", value))?;
+    let target = match target.strip_prefix("path:") {
+        Some(glob) => PreferenceTarget::Path(glob.to_string()),
+        None => PreferenceTarget::Extension(target.trim_start_matches('.').to_lowercase()),
+    };
+    Ok(Preference { target, weight })
+}
+
+/// Merge preferences from the config file with the command line ones, letting the
+/// command line win when both set the same extension or glob
+pub fn merge_preferences(config: &[Preference], cli: &[Preference]) -> Vec<Preference> {
+    let mut merged: Vec<Preference> = config
+        .iter()
+        .filter(|c| !cli.iter().any(|p| p.target == c.target))
+        .cloned()
+        .collect();
+    merged.extend(cli.iter().cloned());
+    merged
+}
+
+pub fn is_test_file(filename: &str) -> bool {
+    TEST_PATTERNS
+        .iter()
+        .any(|pattern| glob_match(pattern, filename))
+}
+
+/// Product of the weights of every preference matching the file, times
+/// `test_weight` for test files
+pub fn preference_multiplier(filename: &str, preferences: &[Preference], test_weight: f32) -> f32 {
+    let extension = Path::new(filename)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase());
+
+    let mut multiplier: f32 = preferences
+        .iter()
+        .filter(|p| match &p.target {
+            PreferenceTarget::Extension(ext) => extension.as_deref() == Some(ext.as_str()),
+            PreferenceTarget::Path(glob) => glob_match(glob, filename),
+        })
+        .map(|p| p.weight)
+        .product();
+
+    if test_weight != 1.0 && is_test_file(filename) {
+        multiplier *= test_weight;
+    }
+    multiplier
+}
@@ -0,0 +1,139 @@
+use crate::scan::FileVectorStore;
+use std::collections::HashMap;
+
+#[derive(Clone)]
+pub struct RecencyOptions {
+    pub half_life_days: f32,
+    pub as_of: Option<u64>,
+    pub from_git: bool,
+}
+
+impl Default for RecencyOptions {
+    fn default() -> Self {
+        RecencyOptions {
+            half_life_days: 30.0,
+            as_of: None,
+            from_git: false,
+        }
+    }
+}
+
+// Days since 1970-01-01 for a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Parse `YYYY-MM-DD`, `YYYY-MM-DDTHH:MM:SS` (UTC) or seconds since the epoch
+pub fn parse_as_of(value: &str) -> Result<u64, String> {
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(seconds);
+    }
+
+    let (date, time) = value.split_once(['T', ' ']).unwrap_or((value, "00:00:00"));
+    let date_parts: Vec<i64> = date
+        .split('-')
+        .map(|p| p.parse::<i64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid date: {}", value))?;
+    let time_parts: Vec<i64> = time
+        .trim_end_matches('Z')
+        .split(':')
+        .map(|p| p.parse::<i64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid time: {}", value))?;
+
+    if date_parts.len() != 3
+        || !(1..=12).contains(&date_parts[1])
+        || !(1..=31).contains(&date_parts[2])
+        || time_parts.is_empty()
+        || time_parts.len() > 3
+    {
+        return Err(format!(
+            "invalid date: {} (expected YYYY-MM-DD or seconds since epoch)",
+            value
+        ));
+    }
+
+    let days = days_from_civil(date_parts[0], date_parts[1], date_parts[2]);
+    let seconds = time_parts[0] * 3600
+        + time_parts.get(1).unwrap_or(&0) * 60
+        + time_parts.get(2).unwrap_or(&0);
+    let timestamp = days * 86400 + seconds;
+    if timestamp < 0 {
+        return Err(format!("date is before 1970: {}", value));
+    }
+    Ok(timestamp as u64)
+}
+
+// Inverse of days_from_civil
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Seconds since the epoch as `YYYY-MM-DD HH:MM:SS UTC`
+pub fn format_timestamp(timestamp: u64) -> String {
+    let (year, month, day) = civil_from_days((timestamp / 86400) as i64);
+    let seconds = timestamp % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Halves every `half_life_days` away from `as_of`, in either direction
+pub fn recency_score(timestamp: u64, as_of: u64, half_life_days: f32) -> f32 {
+    let distance_days = as_of.abs_diff(timestamp) as f32 / 86400.0;
+    if half_life_days <= 0.0 {
+        return if distance_days == 0.0 { 1.0 } else { 0.0 };
+    }
+    0.5_f32.powf(distance_days / half_life_days)
+}
+
+/// Timestamp used for each file's recency: the stored modification time, or the
+/// time of the last commit that touched it
+pub fn file_timestamps(
+    store: &FileVectorStore,
+    options: &RecencyOptions,
+) -> Result<HashMap<String, u64>, Box<dyn std::error::Error>> {
+    let mut timestamps: HashMap<String, u64> = store
+        .bm25_files
+        .iter()
+        .map(|f| (f.filename.clone(), f.last_modified))
+        .collect();
+
+    if options.from_git {
+        let commit_times = crate::git::last_commit_times(options.as_of)?;
+        for (filename, timestamp) in timestamps.iter_mut() {
+            if let Some(commit_time) = commit_times.get(filename) {
+                *timestamp = *commit_time;
+            }
+        }
+    }
+
+    Ok(timestamps)
+}
@@ -0,0 +1,89 @@
+use crate::scan::FileVectorStore;
+use rayon::prelude::*;
+use regex::Regex;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub struct BoostRegex {
+    pub regex: Regex,
+    pub weight: f32,
+}
+
+/// Parse `<regex>` or `<regex>=<weight>`; the weight defaults to 1.0
+pub fn parse_boost_regex(value: &str) -> Result<BoostRegex, String> {
+    let (pattern, weight) = match value.rsplit_once('=') {
+        Some((pattern, weight)) if !pattern.is_empty() => match weight.parse::<f32>() {
+            Ok(weight) => (pattern, weight),
+            Err(_) => (value, 1.0),
+        },
+        _ => (value, 1.0),
+    };
+    let regex = Regex::new(pattern).map_err(|e| format!("invalid regex: {}", e))?;
+    Ok(BoostRegex { regex, weight })
+}
+
+/// Count matches of each regex in the given chunks, reading each file once and
+/// counting files in parallel. Returns the counts per regex keyed by chunk index.
+pub fn count_matches(
+    store: &FileVectorStore,
+    chunk_indices: &[usize],
+    regexes: &[BoostRegex],
+) -> HashMap<usize, Vec<usize>> {
+    let mut chunks_by_file: HashMap<&str, Vec<usize>> = HashMap::new();
+    for &chunk_index in chunk_indices {
+        let filename = store.rag_vectors[chunk_index].filename.as_str();
+        chunks_by_file
+            .entry(filename)
+            .or_default()
+            .push(chunk_index);
+    }
+
+    chunks_by_file
+        .par_iter()
+        .flat_map_iter(|(filename, chunk_indices)| {
+            let contents = std::fs::read_to_string(filename).unwrap_or_default();
+            chunk_indices
+                .iter()
+                .map(|&chunk_index| {
+                    let chunk = &store.rag_vectors[chunk_index];
+                    let text = chunk.text(&contents).unwrap_or("");
+                    let counts = regexes
+                        .iter()
+                        .map(|r| r.regex.find_iter(text).count())
+                        .collect();
+                    (chunk_index, counts)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Normalize each regex's counts by its largest count, then take the weighted
+/// average across regexes, giving a 0-1 score per chunk
+pub fn regex_scores(
+    counts: &HashMap<usize, Vec<usize>>,
+    regexes: &[BoostRegex],
+) -> HashMap<usize, f32> {
+    let total_weight: f32 = regexes.iter().map(|r| r.weight.abs()).sum();
+    let max_counts: Vec<usize> = (0..regexes.len())
+        .map(|i| counts.values().map(|c| c[i]).max().unwrap_or(0))
+        .collect();
+
+    counts
+        .iter()
+        .map(|(&chunk_index, chunk_counts)| {
+            let score: f32 = regexes
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| max_counts[*i] > 0)
+                .map(|(i, r)| r.weight * chunk_counts[i] as f32 / max_counts[i] as f32)
+                .sum();
+            let score = if total_weight > 0.0 {
+                score / total_weight
+            } else {
+                0.0
+            };
+            (chunk_index, score)
+        })
+        .collect()
+}
@@ -0,0 +1,242 @@
+use crate::cache::{hash_key, JsonCache};
+use crate::openai::ChatUsage;
+use crate::scan::{FileVectorStore, RagEmbeddedFileChunk};
+use crate::search::FileMatch;
+use crate::span::LineSpan;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+pub const RERANK_CACHE_FILE: &str = ".luckyshot.rerank.cache.v1";
+
+// Keep prompts small, the start of a chunk is usually enough to grade it
+const MAX_SNIPPET_CHARS: usize = 1500;
+
+const RERANK_SYSTEM_PROMPT: &str = "You grade how relevant code snippets are to a developer's \
+query. Give every snippet an integer grade from 0 (irrelevant) to 10 (exactly what the query \
+is looking for). Grade each snippet on its own merits.";
+
+#[derive(Deserialize)]
+struct Grade {
+    id: usize,
+    grade: i64,
+}
+
+#[derive(Deserialize)]
+struct Grades {
+    grades: Vec<Grade>,
+}
+
+fn grades_schema() -> serde_json::Value {
+    serde_json::json!({
+        "type": "object",
+        "properties": {
+            "grades": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "id": { "type": "integer" },
+                        "grade": { "type": "integer" }
+                    },
+                    "required": ["id", "grade"],
+                    "additionalProperties": false
+                }
+            }
+        },
+        "required": ["grades"],
+        "additionalProperties": false
+    })
+}
+
+fn truncate_chars(text: &str, max_chars: usize) -> &str {
+    match text.char_indices().nth(max_chars) {
+        Some((index, _)) => &text[..index],
+        None => text,
+    }
+}
+
+// The chunk's lines in the current contents of its file, with their span. None when the
+// file can't be read or the chunk can't be found in it.
+fn current_text(
+    store: &FileVectorStore,
+    chunk: &RagEmbeddedFileChunk,
+    files: &mut HashMap<String, Option<String>>,
+) -> Option<(String, LineSpan)> {
+    let contents = files
+        .entry(chunk.filename.clone())
+        .or_insert_with(|| std::fs::read_to_string(&chunk.filename).ok())
+        .as_deref()?;
+    let span = crate::span::current_span(store, chunk, contents)?;
+    let text: Vec<&str> = contents
+        .lines()
+        .skip(span.start_line - 1)
+        .take(span.end_line + 1 - span.start_line)
+        .collect();
+    Some((text.join("\n"), span))
+}
+
+/// Ask the chat model to grade the top `top_n` matches and rescore them by grade.
+/// With `min_grade`, only graded matches at or above it are kept. Grades are cached by
+/// query and chunk contents. Chunks that can't be found in their current file are not
+/// graded. On any error the matches are returned in their original order.
+pub async fn rerank(
+    store: &FileVectorStore,
+    query_text: &str,
+    matches: Vec<FileMatch>,
+    top_n: usize,
+    min_grade: Option<u32>,
+    api_key: &str,
+    debug: bool,
+) -> Vec<FileMatch> {
+    let top_n = top_n.min(matches.len());
+    let mut cache: JsonCache<u32> = JsonCache::load(RERANK_CACHE_FILE);
+
+    // Snippets for the candidates that can be found, keyed by the query and the chunk
+    // contents
+    let mut files = HashMap::new();
+    let candidates: Vec<Option<(String, String, LineSpan)>> = matches[..top_n]
+        .iter()
+        .map(|m| {
+            let chunk = &store.rag_vectors[m.chunk_index];
+            let (text, span) = current_text(store, chunk, &mut files)?;
+            let snippet = truncate_chars(&text, MAX_SNIPPET_CHARS).to_string();
+            Some((hash_key(&[query_text, &hash_key(&[&text])]), snippet, span))
+        })
+        .collect();
+    let found = candidates.iter().flatten().count();
+
+    let mut grades: HashMap<usize, u32> = HashMap::new();
+    let mut uncached = Vec::new();
+    for (i, candidate) in candidates.iter().enumerate() {
+        let Some((key, _, _)) = candidate else {
+            continue;
+        };
+        match cache.get(key) {
+            Some(grade) => {
+                grades.insert(i, grade);
+            }
+            None => uncached.push(i),
+        }
+    }
+
+    let mut usage = ChatUsage::default();
+    if !uncached.is_empty() {
+        let mut prompt = format!("Query: {}\n\nSnippets:\n", query_text);
+        for &i in &uncached {
+            let Some((_, snippet, span)) = &candidates[i] else {
+                continue;
+            };
+            prompt.push_str(&format!(
+                "\n[id {}] {}:{}\n```\n{}\n```\n",
+                i, matches[i].filename, span, snippet
+            ));
+        }
+
+        let response = crate::openai::get_openai_json_completion(
+            &prompt,
+            RERANK_SYSTEM_PROMPT,
+            "snippet_grades",
+            grades_schema(),
+            api_key,
+        )
+        .await;
+        let parsed = response.and_then(|(content, usage)| {
+            let grades: Grades = serde_json::from_str(&content)?;
+            Ok((grades, usage))
+        });
+
+        match parsed {
+            Ok((response, response_usage)) => {
+                usage = response_usage;
+                for graded in response.grades {
+                    if uncached.contains(&graded.id) {
+                        let grade = graded.grade.clamp(0, 10) as u32;
+                        grades.insert(graded.id, grade);
+                        if let Some((key, _, _)) = &candidates[graded.id] {
+                            cache.insert(key.clone(), grade);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("Rerank failed, keeping hybrid order: {}", e);
+                return matches;
+            }
+        }
+
+        if let Err(e) = cache.save() {
+            eprintln!("Error writing rerank cache: {}", e);
+        }
+    }
+
+    if grades.len() < found {
+        eprintln!(
+            "Rerank failed, keeping hybrid order: got grades for {} of {} snippets",
+            grades.len(),
+            found
+        );
+        return matches;
+    }
+
+    let config = crate::config::load_config();
+    let cost = crate::estimate::chat_cost(crate::openai::CHAT_MODEL, &usage, &config)
+        .map_or("unknown".to_string(), |cost| format!("${:.4}", cost));
+    eprintln!(
+        "Rerank: graded {} chunks ({} cached), {} prompt + {} completion tokens, cost {}",
+        found,
+        found - uncached.len(),
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        cost
+    );
+
+    let mut matches = matches;
+    let rest = matches.split_off(top_n);
+    // The grade becomes the score, with the hybrid score breaking ties between equal
+    // grades, so later sorting and thresholds follow the rerank. Chunks that weren't
+    // found join the ungraded rest, ahead of it in hybrid order.
+    let (mut graded, mut rest): (Vec<FileMatch>, Vec<FileMatch>) = matches
+        .into_iter()
+        .enumerate()
+        .map(|(i, mut m)| {
+            if let Some(&grade) = grades.get(&i) {
+                m.components.rerank_grade = Some(grade);
+                m.similarity = (grade as f32 + m.similarity.clamp(0.0, 1.0)) / 11.0;
+            }
+            m
+        })
+        .chain(rest)
+        .partition(|m| m.components.rerank_grade.is_some());
+    graded.sort_by(|a, b| b.similarity.partial_cmp(&a.similarity).unwrap());
+    if let Some(min_grade) = min_grade {
+        graded.retain(|m| m.components.rerank_grade >= Some(min_grade));
+    }
+
+    if debug {
+        println!("Rerank grades:");
+        for m in &graded {
+            println!(
+                "{} ({}) {}",
+                m.components.rerank_grade.unwrap_or(0),
+                m.similarity,
+                m.filename
+            );
+        }
+        println!("\n");
+    }
+
+    // Ungraded candidates can't be held to the minimum grade, so they are dropped with
+    // it. Otherwise they are scaled to stay below the graded ones, in hybrid order.
+    if min_grade.is_some() {
+        rest.clear();
+    }
+    let lowest_graded = graded.last().map_or(0.0, |m| m.similarity);
+    let best_rest = rest.first().map_or(0.0, |m| m.similarity);
+    if best_rest > lowest_graded && best_rest > 0.0 {
+        for m in &mut rest {
+            m.similarity *= lowest_graded / best_rest;
+        }
+    }
+    graded.extend(rest);
+    graded
+}
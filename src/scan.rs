@@ -1,8 +1,15 @@
+use crate::cache::EmbeddingCache;
+use crate::span::{line_span, ChunkEnds, LineSpan};
+use crate::symbols::Symbol;
 use crate::tokenizer::get_tokenizer;
 use bm25::Tokenizer;
+use glob_match::glob_match;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 
+pub const VECTORS_FILE: &str = ".luckyshot.file.vectors.v1";
+
 #[derive(Serialize, Deserialize)]
 pub struct FileVectorStore {
     pub rag_vectors: Vec<RagEmbeddedFileChunk>,
@@ -14,6 +21,12 @@ pub struct FileVectorStore {
     pub date: u64,
     pub bm25_avgdl: f32,
     pub doc_count: usize,
+    #[serde(default)]
+    pub git_rev: Option<String>, // Commit the index was built from, if scanned with --rev
+    #[serde(default)]
+    pub dependencies: HashMap<String, Vec<String>>, // Scanned files each file imports
+    #[serde(default)]
+    pub symbols: Vec<Symbol>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -25,6 +38,8 @@ pub struct Bm25EmbeddedFile {
     pub token_count: usize,
     pub last_modified: u64,
     pub has_metadata: bool, // Whether metadata was included in the embedding
+    #[serde(default)]
+    pub blob_oid: String, // Git blob id of the contents, used to detect real changes
 }
 
 #[derive(Serialize, Deserialize)]
@@ -36,15 +51,184 @@ pub struct RagEmbeddedFileChunk {
     pub chunk_size: usize,   // Size of this chunk (might be smaller for last chunk)
     pub is_full_file: bool,  // Whether this is a full file embedding or a chunk
     pub has_metadata: bool,  // Whether metadata was included in the embedding
+    #[serde(default)]
+    pub span: LineSpan, // Lines and columns of the chunk when it was scanned
+    #[serde(default)]
+    pub ends: ChunkEnds, // First and last lines of the chunk, to find it after changes
 }
 
-pub async fn scan_files(
+impl RagEmbeddedFileChunk {
+    /// This chunk's slice of the file contents, if it still fits
+    pub fn text<'a>(&self, contents: &'a str) -> Option<&'a str> {
+        contents.get(self.chunk_offset..self.chunk_offset + self.chunk_size)
+    }
+}
+
+pub fn create_chunks(
+    content: &str,
+    chunk_size: usize,
+    overlap_size: usize,
+) -> Vec<(usize, String)> {
+    if chunk_size == 0 {
+        return vec![(0, content.to_string())];
+    }
+
+    let mut chunks = Vec::new();
+    let content_len = content.len();
+    let mut offset = 0;
+
+    while offset < content_len {
+        let end = (offset + chunk_size).min(content_len);
+        let chunk = content[offset..end].to_string();
+        chunks.push((offset, chunk));
+
+        if end == content_len {
+            break;
+        }
+
+        offset += chunk_size - overlap_size;
+    }
+
+    chunks
+}
+
+/// Where scan reads the list of files and their contents from
+pub enum ScanSource {
+    /// Walk the working tree, respecting .gitignore
+    Walk,
+    /// Files tracked in the git index, read from the working tree
+    GitIndex,
+    /// Files in a committed snapshot, read from the object database
+    GitRev(String),
+}
+
+pub struct SourceFile {
+    pub path_str: String,
+    pub contents: String,
+    pub last_modified: u64,
+    pub size: u64,
+    pub blob_oid: String,
+}
+
+/// Read a file from the working tree
+pub fn read_working_file(
+    path: &std::path::Path,
+    path_str: String,
+) -> Result<SourceFile, Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(path)?;
+    let metadata = fs::metadata(path)?;
+    let last_modified = metadata
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    Ok(SourceFile {
+        path_str,
+        blob_oid: crate::git::blob_oid(contents.as_bytes()),
+        contents,
+        last_modified,
+        size: metadata.len(),
+    })
+}
+
+/// Read every file matching `pattern` from the given source
+pub fn collect_source_files(
+    pattern: &str,
+    source: &ScanSource,
+) -> Result<Vec<SourceFile>, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+
+    match source {
+        ScanSource::Walk => {
+            let current_dir = std::env::current_dir()?;
+            for path in crate::files::find_matching_files(pattern) {
+                let relative_path = path.strip_prefix(&current_dir).unwrap_or(&path);
+                let path_str = relative_path.to_string_lossy().to_string();
+
+                // Skip the vectors file
+                if path_str.ends_with(VECTORS_FILE) {
+                    continue;
+                }
+
+                files.push(read_working_file(&path, path_str)?);
+            }
+        }
+        ScanSource::GitIndex => {
+            for git_file in crate::git::tracked_files(None)? {
+                if !glob_match(pattern, &git_file.path) {
+                    continue;
+                }
+                let path = std::path::Path::new(&git_file.path);
+                // Deleted in the working tree but still staged
+                if !path.is_file() {
+                    continue;
+                }
+                files.push(read_working_file(path, git_file.path.clone())?);
+            }
+        }
+        ScanSource::GitRev(rev) => {
+            let commit_time = crate::git::commit_time(rev)?;
+            for git_file in crate::git::tracked_files(Some(rev))? {
+                if !glob_match(pattern, &git_file.path) {
+                    continue;
+                }
+                let blob = crate::git::read_blob(&git_file.oid)?;
+                let contents = String::from_utf8(blob)
+                    .map_err(|e| format!("{} is not valid UTF-8: {}", git_file.path, e))?;
+                files.push(SourceFile {
+                    path_str: git_file.path,
+                    size: contents.len() as u64,
+                    contents,
+                    last_modified: commit_time,
+                    blob_oid: git_file.oid,
+                });
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+fn load_previous_store() -> Option<FileVectorStore> {
+    let content = fs::read_to_string(VECTORS_FILE).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// The last scan's store, if its files were chunked the same way so their embeddings
+/// can be reused
+pub fn load_reusable_store(
+    chunk_size: usize,
+    overlap_size: usize,
+    embed_metadata: bool,
+) -> Option<FileVectorStore> {
+    load_previous_store().filter(|previous| {
+        previous.chunk_size == chunk_size
+            && previous.overlap_size == overlap_size
+            && previous.embed_metadata == embed_metadata
+    })
+}
+
+/// Whether the file's blob is unchanged since `previous` was scanned. Metadata embeddings
+/// include the modification time, so that has to match as well.
+pub fn is_unchanged(previous: &FileVectorStore, file: &SourceFile) -> bool {
+    previous.bm25_files.iter().any(|f| {
+        f.filename == file.path_str
+            && !f.blob_oid.is_empty()
+            && f.blob_oid == file.blob_oid
+            && (!previous.embed_metadata || f.last_modified == file.last_modified)
+    })
+}
+
+/// Read, chunk and embed the matching files into a new store. Embeddings are reused
+/// from the last scan for unchanged files, and from `chunk_cache` when given.
+pub async fn build_store(
     pattern: &str,
     api_key: &str,
     chunk_size: usize,
     overlap_size: usize,
     embed_metadata: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
+    source: &ScanSource,
+    chunk_cache: Option<&EmbeddingCache>,
+) -> Result<FileVectorStore, Box<dyn std::error::Error>> {
     if chunk_size > 0 && overlap_size >= chunk_size {
         return Err("overlap_size must be less than chunk_size".into());
     }
@@ -53,202 +237,227 @@ pub async fn scan_files(
         rag_vectors: Vec::new(),
         bm25_files: Vec::new(),
         pattern: pattern.to_string(),
-        chunk_size: chunk_size,
-        overlap_size: overlap_size,
-        embed_metadata: embed_metadata,
+        chunk_size,
+        overlap_size,
+        embed_metadata,
         date: std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_secs(),
         bm25_avgdl: 0.0,
         doc_count: 0,
+        git_rev: match source {
+            ScanSource::GitRev(rev) => Some(crate::git::resolve_rev(rev)?),
+            _ => None,
+        },
+        dependencies: HashMap::new(),
+        symbols: Vec::new(),
     };
 
+    // Embeddings from the last scan can be reused for files whose contents haven't changed,
+    // as long as they were chunked the same way
+    let previous = load_reusable_store(chunk_size, overlap_size, embed_metadata);
+
     // For calculating average document length
     let mut total_tokens = 0;
     let mut doc_count = 0;
 
-    fn create_chunks(
-        content: &str,
-        chunk_size: usize,
-        overlap_size: usize,
-    ) -> Vec<(usize, String)> {
-        if chunk_size == 0 {
-            return vec![(0, content.to_string())];
-        }
-
-        let mut chunks = Vec::new();
-        let content_len = content.len();
-        let mut offset = 0;
-
-        while offset < content_len {
-            let end = (offset + chunk_size).min(content_len);
-            let chunk = content[offset..end].to_string();
-            chunks.push((offset, chunk));
+    // Find and read all matching files
+    let source_files = collect_source_files(pattern, source)?;
 
-            if end == content_len {
-                break;
-            }
+    // First pass: calculate true average document length
+    println!("Calculating average document length...");
+    for file in source_files.iter() {
+        let tokens = get_tokenizer().tokenize(&bm25_text(file, embed_metadata));
+        total_tokens += tokens.len();
+        doc_count += 1;
+    }
+    store.bm25_avgdl = total_tokens as f32 / doc_count as f32;
+    store.doc_count = doc_count;
+    println!("Average document length: {:.2}", store.bm25_avgdl);
 
-            offset += chunk_size - overlap_size;
-        }
+    store.dependencies = crate::deps::dependency_graph(&source_files);
+    println!(
+        "Found imports in {} of {} files",
+        store.dependencies.len(),
+        source_files.len()
+    );
+    store.symbols = crate::symbols::extract_symbols(&source_files);
+    println!("Found {} symbols", store.symbols.len());
 
-        chunks
+    // Second pass: process each file
+    for file in source_files.iter() {
+        index_file(file, &mut store, previous.as_ref(), api_key, chunk_cache).await?;
     }
 
-    async fn process_file(
-        path: &std::path::Path,
-        path_str: &str,
-        store: &mut FileVectorStore,
-        api_key: &str,
-        chunk_size: usize,
-        overlap_size: usize,
-        embed_metadata: bool,
-        _total_tokens: &mut usize,
-        _doc_count: &mut usize,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Processing: {}", path_str);
-
-        // Read the file contents
-        let contents = fs::read_to_string(path)?;
-        let metadata = fs::metadata(path)?;
-        let last_modified = metadata
-            .modified()?
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_secs();
+    Ok(store)
+}
 
-        // Phase 1: Create BM25 embedding for the entire file
-        let content_to_embed = if embed_metadata {
-            let size = metadata.len();
-            crate::metadata::prepend_metadata(path_str, last_modified, size, &contents)
-        } else {
-            contents.clone()
-        };
+/// The text of a file's BM25 vector, with the metadata prefix when it is embedded.
+/// Its token count is the document length the average is taken over.
+fn bm25_text(file: &SourceFile, embed_metadata: bool) -> String {
+    if embed_metadata {
+        crate::metadata::prepend_metadata(
+            &file.path_str,
+            file.last_modified,
+            file.size,
+            &file.contents,
+        )
+    } else {
+        file.contents.clone()
+    }
+}
 
-        // Generate BM25 vector for the entire file
-        let bm25_vec =
-            crate::bm25_embedder::create_bm25_vector(&content_to_embed, store.bm25_avgdl);
-
-        // Tokenize and deduplicate tokens for BM25
-        let tokens = get_tokenizer().tokenize(&content_to_embed);
-
-        // Store the BM25 embedding
-        store.bm25_files.push(Bm25EmbeddedFile {
-            filename: path_str.to_string(),
-            bm25_indices: bm25_vec.indices,
-            bm25_values: bm25_vec.values,
-            tokens: tokens.clone(),
-            token_count: tokens.len(),
-            last_modified,
-            has_metadata: embed_metadata,
-        });
-
-        // Phase 2: Create RAG embeddings for chunks
-        let chunks = create_chunks(&contents, chunk_size, overlap_size);
-
-        for (offset, chunk_content) in chunks {
-            let chunk_to_embed = if embed_metadata {
-                let size = metadata.len();
-                crate::metadata::prepend_metadata(path_str, last_modified, size, &chunk_content)
-            } else {
-                chunk_content.clone()
-            };
-
-            match crate::openai::get_embedding(&chunk_to_embed, api_key).await {
-                Ok(embedding) => {
-                    store.rag_vectors.push(RagEmbeddedFileChunk {
-                        filename: path_str.to_string(),
-                        vector: embedding,
-                        last_modified,
-                        chunk_offset: offset,
-                        chunk_size: chunk_content.len(),
-                        is_full_file: chunk_size == 0,
-                        has_metadata: embed_metadata,
-                    });
-
-                    if chunk_size > 0 {
-                        println!("Got embedding for {} (chunk offset: {})", path_str, offset);
-                    } else {
-                        println!("Got embedding for {}", path_str);
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Error getting embedding for {}: {}", path_str, e);
-                    return Err(e);
-                }
+/// Add a file's BM25 vector and RAG embeddings to the store. Embeddings are reused from
+/// `previous` if the file is unchanged since then, and from `chunk_cache` when given.
+pub async fn index_file(
+    file: &SourceFile,
+    store: &mut FileVectorStore,
+    previous: Option<&FileVectorStore>,
+    api_key: &str,
+    chunk_cache: Option<&EmbeddingCache>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (chunk_size, overlap_size, embed_metadata) =
+        (store.chunk_size, store.overlap_size, store.embed_metadata);
+    let path_str = file.path_str.as_str();
+    let contents = &file.contents;
+    let last_modified = file.last_modified;
+    println!("Processing: {}", path_str);
+
+    // Phase 1: Create BM25 embedding for the entire file
+    let content_to_embed = bm25_text(file, embed_metadata);
+
+    // Generate BM25 vector for the entire file
+    let bm25_vec = crate::bm25_embedder::create_bm25_vector(&content_to_embed, store.bm25_avgdl);
+
+    // Tokenize and deduplicate tokens for BM25
+    let tokens = get_tokenizer().tokenize(&content_to_embed);
+
+    // Store the BM25 embedding
+    store.bm25_files.push(Bm25EmbeddedFile {
+        filename: path_str.to_string(),
+        bm25_indices: bm25_vec.indices,
+        bm25_values: bm25_vec.values,
+        tokens: tokens.clone(),
+        token_count: tokens.len(),
+        last_modified,
+        has_metadata: embed_metadata,
+        blob_oid: file.blob_oid.clone(),
+    });
+
+    // Reuse the RAG embeddings if the blob is unchanged since the last scan
+    if let Some(previous) = previous {
+        if is_unchanged(previous, file) {
+            for chunk in previous
+                .rag_vectors
+                .iter()
+                .filter(|c| c.filename == path_str)
+            {
+                store.rag_vectors.push(RagEmbeddedFileChunk {
+                    filename: chunk.filename.clone(),
+                    vector: chunk.vector.clone(),
+                    last_modified,
+                    chunk_offset: chunk.chunk_offset,
+                    chunk_size: chunk.chunk_size,
+                    is_full_file: chunk.is_full_file,
+                    has_metadata: chunk.has_metadata,
+                    // Recomputed, indexes from before spans were recorded lack them
+                    span: line_span(contents, chunk.chunk_offset, chunk.chunk_size),
+                    ends: ChunkEnds::of(chunk.text(contents).unwrap_or_default()),
+                });
             }
+            println!("Unchanged, reusing embeddings for {}", path_str);
+            return Ok(());
         }
-
-        Ok(())
     }
 
-    // Find all matching files
-    let matching_files = crate::files::find_matching_files(pattern);
+    // Phase 2: Create RAG embeddings for chunks
+    let chunks = create_chunks(contents, chunk_size, overlap_size);
 
-    // First pass: calculate true average document length
-    println!("Calculating average document length...");
-    for path in matching_files.iter() {
-        let relative_path = path.strip_prefix(std::env::current_dir()?).unwrap_or(&path);
-        let path_str = relative_path.to_string_lossy().to_string();
+    for (offset, chunk_content) in chunks {
+        let chunk_to_embed = if embed_metadata {
+            crate::metadata::prepend_metadata(path_str, last_modified, file.size, &chunk_content)
+        } else {
+            chunk_content.clone()
+        };
 
-        // Skip the vectors file
-        if path_str.ends_with(".luckyshot.file.vectors.v1") {
-            continue;
+        let embedding = match chunk_cache {
+            Some(cache) => cache.embed(&chunk_to_embed, api_key).await,
+            None => crate::openai::get_embedding(&chunk_to_embed, api_key).await,
+        };
+        match embedding {
+            Ok(embedding) => {
+                store.rag_vectors.push(RagEmbeddedFileChunk {
+                    filename: path_str.to_string(),
+                    vector: embedding,
+                    last_modified,
+                    chunk_offset: offset,
+                    chunk_size: chunk_content.len(),
+                    is_full_file: chunk_size == 0,
+                    has_metadata: embed_metadata,
+                    span: line_span(contents, offset, chunk_content.len()),
+                    ends: ChunkEnds::of(&chunk_content),
+                });
+
+                if chunk_size > 0 {
+                    println!("Got embedding for {} (chunk offset: {})", path_str, offset);
+                } else {
+                    println!("Got embedding for {}", path_str);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error getting embedding for {}: {}", path_str, e);
+                return Err(e);
+            }
         }
-
-        let contents = fs::read_to_string(path)?;
-        let tokens = get_tokenizer().tokenize(&contents);
-        total_tokens += tokens.len();
-        doc_count += 1;
     }
-    store.bm25_avgdl = total_tokens as f32 / doc_count as f32;
-    store.doc_count = doc_count;
-    println!("Average document length: {:.2}", store.bm25_avgdl);
 
-    // Second pass: process each file
-    for path in matching_files {
-        let relative_path = path.strip_prefix(std::env::current_dir()?).unwrap_or(&path);
-        let path_str = relative_path.to_string_lossy().to_string();
+    Ok(())
+}
 
-        // Skip the vectors file
-        if path_str.ends_with(".luckyshot.file.vectors.v1") {
-            continue;
-        }
+/// Write the store to the vectors file
+pub fn save_store(store: &FileVectorStore) -> Result<(), Box<dyn std::error::Error>> {
+    let json = serde_json::to_string_pretty(store)
+        .map_err(|e| format!("Error serializing vectors: {}", e))?;
+    fs::write(VECTORS_FILE, json).map_err(|e| format!("Error writing vectors file: {}", e))?;
+    Ok(())
+}
 
-        process_file(
-            &path,
-            &path_str,
-            &mut store,
-            api_key,
-            chunk_size,
-            overlap_size,
-            embed_metadata,
-            &mut total_tokens,
-            &mut doc_count,
-        )
-        .await?;
-    }
+pub async fn scan_files(
+    pattern: &str,
+    api_key: &str,
+    chunk_size: usize,
+    overlap_size: usize,
+    embed_metadata: bool,
+    source: &ScanSource,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let store = build_store(
+        pattern,
+        api_key,
+        chunk_size,
+        overlap_size,
+        embed_metadata,
+        source,
+        None,
+    )
+    .await?;
 
     // Save embeddings to file
-    match serde_json::to_string_pretty(&store) {
-        Ok(json) => {
-            if let Err(e) = fs::write(".luckyshot.file.vectors.v1", json) {
-                eprintln!("Error writing vectors file: {}", e);
-            } else {
-                println!(
-                    "Successfully saved vectors for {} chunks",
-                    store.rag_vectors.len()
-                );
-
-                // Print celebratory figlet
-                use colored::*;
-                use figlet_rs::FIGfont;
-
-                let standard_font = FIGfont::standard().unwrap();
-                let figure = standard_font.convert("Yee-haw!").unwrap();
-                println!("\n{}", figure.to_string().bright_yellow());
-            }
+    match save_store(&store) {
+        Ok(()) => {
+            println!(
+                "Successfully saved vectors for {} chunks",
+                store.rag_vectors.len()
+            );
+
+            // Print celebratory figlet
+            use colored::*;
+            use figlet_rs::FIGfont;
+
+            let standard_font = FIGfont::standard().unwrap();
+            let figure = standard_font.convert("Yee-haw!").unwrap();
+            println!("\n{}", figure.to_string().bright_yellow());
         }
-        Err(e) => eprintln!("Error serializing vectors: {}", e),
+        Err(e) => eprintln!("{}", e),
     }
 
     Ok(())
@@ -1,91 +1,268 @@
+use crate::cache::EmbeddingCache;
+use crate::expand::{Expansion, ExpansionKind};
+use crate::hyde::HydeFusion;
+use crate::pack::PackOptions;
+use crate::preference::Preference;
+use crate::recency::RecencyOptions;
+use crate::regex_boost::BoostRegex;
 use crate::scan::FileVectorStore;
+use bm25::ScoredDocument;
+use clap::ValueEnum;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FileMatch {
     pub filename: String,
     pub similarity: f32,
+    pub chunk_index: usize, // Index of the matched chunk in FileVectorStore::rag_vectors
+    pub expansion: Option<Expansion>, // Set for neighbors added after ranking
+    pub components: ScoreComponents,
 }
 
-pub async fn find_related_files(
-    query_text: &str,
-    api_key: &str,
-    filter_similarity: f32,
-    verbose: bool,
-    debug: bool,
-    file_contents: bool,
-    count: usize,
-    bm25_scale: f32,
-    rag_scale: f32,
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    // Load the vectors file
-    let vectors_content = match fs::read_to_string(".luckyshot.file.vectors.v1") {
-        Ok(content) => content,
-        Err(e) => {
-            eprintln!("Error reading vectors file: {}", e);
-            return Ok(Vec::new());
-        }
-    };
+/// The signals behind a match's hybrid score, before they are scaled and fused
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ScoreComponents {
+    pub rag: f32,                  // Normalized embedding similarity of the chunk
+    pub bm25: f32,                 // Normalized BM25 score of the file
+    pub recency: f32,              // Recency score of the file
+    pub regex: f32,                // Normalized regex match score of the chunk
+    pub preference: f32,           // Extension, path and test file multiplier
+    pub rerank_grade: Option<u32>, // Chat model grade, if reranked
+}
 
-    // Parse the JSON
-    let store: FileVectorStore = match serde_json::from_str(&vectors_content) {
-        Ok(embeddings) => embeddings,
-        Err(e) => {
-            eprintln!("Error parsing vectors file: {}", e);
-            return Ok(Vec::new());
+impl Default for ScoreComponents {
+    fn default() -> Self {
+        ScoreComponents {
+            rag: 0.0,
+            bm25: 0.0,
+            recency: 0.0,
+            regex: 0.0,
+            preference: 1.0,
+            rerank_grade: None,
         }
-    };
+    }
+}
 
-    // Perform BM25 ranking
-    let mut bm25_results = crate::bm25_ranker::rank_documents(&store, query_text, store.bm25_avgdl);
+#[derive(Clone, Debug)]
+pub struct WeightedQuery {
+    pub text: String,
+    pub weight: f32,
+}
 
-    // Get query embedding and calculate similarity for each file
-    let query_embedding = match crate::openai::get_embedding(query_text, api_key).await {
-        Ok(embedding) => embedding,
-        Err(e) => {
-            eprintln!("Error getting query embedding: {}", e);
-            return Ok(Vec::new());
+impl WeightedQuery {
+    pub fn new(text: &str) -> Self {
+        WeightedQuery {
+            text: text.to_string(),
+            weight: 1.0,
+        }
+    }
+}
+
+impl std::fmt::Display for WeightedQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.weight == 1.0 {
+            write!(f, "{}", self.text)
+        } else {
+            write!(f, "{} x{}", self.text, self.weight)
         }
+    }
+}
+
+/// Parse `<prompt>` or `<prompt>^<weight>`; the weight defaults to 1.0. Only a plain
+/// decimal after the last `^` is a weight, and a `^` right after a digit is left alone,
+/// so prompts like `set MAX_RETRIES=5` or `2^10` stay as written.
+pub fn parse_weighted_query(value: &str) -> Result<WeightedQuery, String> {
+    let weight_suffix = Regex::new(r"^(.*\D)\^(\d+(?:\.\d*)?|\.\d+)$").unwrap();
+    let Some(captures) = weight_suffix.captures(value) else {
+        return Ok(WeightedQuery::new(value));
     };
+    let text = &captures[1];
+    let weight: f32 = captures[2]
+        .parse()
+        .map_err(|e| format!("Invalid weight in {}: {}", value, e))?;
+    if text.trim().is_empty() {
+        return Err(format!("No prompt before the weight in {}", value));
+    }
+    if !(weight.is_finite() && weight > 0.0) {
+        return Err(format!("The weight in {} must be above 0", value));
+    }
+    Ok(WeightedQuery {
+        text: text.to_string(),
+        weight,
+    })
+}
 
-    let mut matches: Vec<FileMatch> = store
+/// The positive queries joined into one, for stages that take a single query
+pub fn combined_query_text(queries: &[WeightedQuery]) -> String {
+    queries
+        .iter()
+        .map(|q| q.text.as_str())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn print_query_similarities(store: &FileVectorStore, query: &WeightedQuery, embedding: &[f32]) {
+    println!("RAG similarities ({}):", query);
+    let mut similarities: Vec<(f32, &str)> = store
         .rag_vectors
         .iter()
-        .map(|embedding| {
-            let similarity =
-                crate::similarity::dot_product_similarity(&query_embedding, &embedding.vector);
-            FileMatch {
-                filename: embedding.filename.clone(),
-                similarity,
-            }
+        .map(|e| {
+            (
+                crate::similarity::dot_product_similarity(embedding, &e.vector),
+                e.filename.as_str(),
+            )
         })
         .collect();
+    similarities.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+    for (similarity, filename) in similarities {
+        println!("{} {}", similarity, filename);
+    }
+    println!("\n");
+}
 
-    // Sort matches by similarity
-    matches.sort_by(|a, b| b.similarity.partial_cmp(&a.similarity).unwrap());
+// Rank offset of reciprocal rank fusion, damping the lead of the very top ranks
+const RRF_K: f32 = 60.0;
 
-    if debug {
-        // print out the BM25 results
-        println!("\nBM25 ranks:");
-        for scored_doc in &bm25_results {
-            let doc_index = scored_doc.id as usize;
-            if doc_index < store.bm25_files.len() {
-                println!(
-                    "{}: {}",
-                    scored_doc.score, store.bm25_files[doc_index].filename
-                );
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fusion {
+    /// Add the normalized scores, each times its scale
+    Weighted,
+    /// Add scale / (60 + rank) of each ranking (reciprocal rank fusion)
+    Rrf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    /// A file scores its best chunk
+    Max,
+    /// A file scores the mean of its chunks
+    Mean,
+    /// A file scores the sum of its chunks, favoring files with many matches
+    Sum,
+}
+
+/// One match per file, keeping its best chunk and in order of the best chunks, scored
+/// by `aggregation` of its chunk scores. Mean and sum reorder the files by that score.
+fn aggregate_chunks(matches: Vec<FileMatch>, aggregation: Aggregation) -> Vec<FileMatch> {
+    let mut files: Vec<(FileMatch, Vec<f32>)> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for m in matches {
+        match positions.get(&m.filename) {
+            Some(&i) => files[i].1.push(m.similarity),
+            None => {
+                positions.insert(m.filename.clone(), files.len());
+                let similarity = m.similarity;
+                files.push((m, vec![similarity]));
             }
         }
-        println!("\n");
+    }
 
-        //print out the RAG vector distances and filename, in descending
-        println!("RAG distances:");
-        for m in &matches {
-            println!("{} {}", m.similarity, m.filename);
+    let mut aggregated: Vec<FileMatch> = files
+        .into_iter()
+        .map(|(mut m, scores)| {
+            m.similarity = match aggregation {
+                Aggregation::Max => m.similarity,
+                Aggregation::Mean => scores.iter().sum::<f32>() / scores.len() as f32,
+                Aggregation::Sum => scores.iter().sum(),
+            };
+            m
+        })
+        .collect();
+    if aggregation != Aggregation::Max {
+        aggregated.sort_by(|a, b| b.similarity.partial_cmp(&a.similarity).unwrap());
+    }
+    aggregated
+}
+
+// 1-based rank of each key by descending score
+fn ranks<K: std::hash::Hash + Eq>(scores: impl Iterator<Item = (K, f32)>) -> HashMap<K, usize> {
+    let mut sorted: Vec<(K, f32)> = scores.collect();
+    sorted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    sorted
+        .into_iter()
+        .enumerate()
+        .map(|(i, (key, _))| (key, i + 1))
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct SearchOptions {
+    pub filter_similarity: f32,
+    pub verbose: bool,
+    pub debug: bool,
+    pub file_contents: bool,
+    pub count: usize,
+    pub bm25_scale: f32,
+    pub rag_scale: f32,
+    pub fusion: Fusion,
+    pub aggregation: Aggregation,
+    pub recency_scale: f32,
+    pub recency: RecencyOptions,
+    pub boost_regexes: Vec<BoostRegex>,
+    pub regex_scale: f32,
+    pub boost_regex_top: usize, // Only count regex matches for this many top candidates (0 for all)
+    pub preferences: Vec<Preference>,
+    pub test_weight: f32,
+    pub rerank: usize, // Number of top candidates for the chat model to rerank (0 for off)
+    pub rerank_min_grade: Option<u32>,
+    pub hyde: bool,
+    pub hyde_fusion: HydeFusion,
+    pub negative_queries: Vec<WeightedQuery>,
+    pub pack: Option<PackOptions>, // Print a token-budgeted context pack instead of filenames
+    pub expand_cochange: usize,    // Number of co-changed files to add (0 for off)
+    pub cochange_weight: f32,
+    pub expand_deps: usize, // Number of imported and importing files to add (0 for off)
+    pub deps_weight: f32,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            filter_similarity: 0.0,
+            verbose: false,
+            debug: false,
+            file_contents: false,
+            count: 0,
+            bm25_scale: 0.1,
+            rag_scale: 1.0,
+            fusion: Fusion::Weighted,
+            aggregation: Aggregation::Max,
+            recency_scale: 0.0,
+            recency: RecencyOptions::default(),
+            boost_regexes: Vec::new(),
+            regex_scale: 0.5,
+            boost_regex_top: 0,
+            preferences: Vec::new(),
+            test_weight: 1.0,
+            rerank: 0,
+            rerank_min_grade: None,
+            hyde: false,
+            hyde_fusion: HydeFusion::Average,
+            negative_queries: Vec::new(),
+            pack: None,
+            expand_cochange: 0,
+            cochange_weight: 0.5,
+            expand_deps: 0,
+            deps_weight: 0.5,
         }
-        println!("\n");
     }
+}
 
+pub fn load_store() -> Result<FileVectorStore, Box<dyn std::error::Error>> {
+    let vectors_content = fs::read_to_string(crate::scan::VECTORS_FILE)
+        .map_err(|e| format!("Error reading vectors file: {}", e))?;
+    let store = serde_json::from_str(&vectors_content)
+        .map_err(|e| format!("Error parsing vectors file: {}", e))?;
+    Ok(store)
+}
+
+// Normalize BM25 scores to the -1 to 1 range, preserving sign
+fn normalize_bm25_scores(bm25_results: &mut [ScoredDocument<u32>]) {
     // I want you to normalize the BM25 numbers, but I need you to do it in way that gives range 1 to -1
     // Find min and max BM25 scores
     let min_bm25 = bm25_results
@@ -114,12 +291,176 @@ pub async fn find_related_files(
 
         m.score = normalized;
     });
+}
+
+/// Score every chunk in `store` against the weighted queries, best first
+pub async fn score_chunks(
+    store: &FileVectorStore,
+    queries: &[WeightedQuery],
+    api_key: &str,
+    options: &SearchOptions,
+    embeddings: &EmbeddingCache,
+) -> Result<Vec<FileMatch>, Box<dyn std::error::Error>> {
+    let debug = options.debug;
+    if queries.is_empty() {
+        return Err("No query given".into());
+    }
+    let query_text = combined_query_text(queries);
+    let total_weight: f32 = queries.iter().map(|q| q.weight).sum();
+    if total_weight <= 0.0 {
+        return Err("Query weights must add up to more than 0".into());
+    }
+
+    // Optionally expand the query with a hypothetical answer (HyDE)
+    let mut hyde_identifiers = String::new();
+    let mut hyde_embedding: Option<Vec<f32>> = None;
+    if options.hyde {
+        match crate::hyde::hypothetical_document(&query_text, api_key).await {
+            Ok(document) => {
+                let identifiers = crate::hyde::extract_identifiers(&document);
+                if debug {
+                    println!("\nHyDE document:\n{}\n", document);
+                    println!("HyDE identifiers: {}\n", identifiers.join(" "));
+                }
+                match embeddings.embed(&document, api_key).await {
+                    Ok(embedding) => {
+                        hyde_identifiers = identifiers.join(" ");
+                        hyde_embedding = Some(embedding);
+                    }
+                    Err(e) => eprintln!("HyDE failed, using the original query: {}", e),
+                }
+            }
+            Err(e) => eprintln!("HyDE failed, using the original query: {}", e),
+        }
+    }
+
+    // Perform BM25 ranking for each query, then fuse the normalized scores by weight,
+    // subtracting negative queries
+    let mut bm25_scores: HashMap<u32, f32> = HashMap::new();
+    let bm25_queries = queries
+        .iter()
+        .map(|q| (q, 1.0))
+        .chain(options.negative_queries.iter().map(|q| (q, -1.0)));
+    for (query, sign) in bm25_queries {
+        let bm25_query = if sign > 0.0 && !hyde_identifiers.is_empty() {
+            format!("{} {}", query.text, hyde_identifiers)
+        } else {
+            query.text.clone()
+        };
+        let mut bm25_results =
+            crate::bm25_ranker::rank_documents(store, &bm25_query, store.bm25_avgdl);
+
+        if debug {
+            // print out the BM25 results
+            println!(
+                "\nBM25 ranks ({}{}):",
+                if sign < 0.0 { "not " } else { "" },
+                query
+            );
+            for scored_doc in &bm25_results {
+                let doc_index = scored_doc.id as usize;
+                if doc_index < store.bm25_files.len() {
+                    println!(
+                        "{}: {}",
+                        scored_doc.score, store.bm25_files[doc_index].filename
+                    );
+                }
+            }
+            println!("\n");
+        }
+
+        normalize_bm25_scores(&mut bm25_results);
+        for scored_doc in &bm25_results {
+            *bm25_scores.entry(scored_doc.id).or_insert(0.0) +=
+                sign * query.weight * scored_doc.score / total_weight;
+        }
+    }
 
     if debug {
         // print out normalized BM25 distances
         println!("Normalized BM25 distances:");
-        for m in &bm25_results {
-            println!("{} {}", m.score, store.bm25_files[m.id as usize].filename);
+        let mut sorted: Vec<_> = bm25_scores.iter().collect();
+        sorted.sort_by(|a, b| b.1.partial_cmp(a.1).unwrap());
+        for (id, score) in sorted {
+            println!("{} {}", score, store.bm25_files[*id as usize].filename);
+        }
+        println!("\n");
+    }
+
+    // Get query embeddings and combine them into one weighted query vector
+    let mut query_embedding: Vec<f32> = Vec::new();
+    for query in queries {
+        let embedding = embeddings
+            .embed(&query.text, api_key)
+            .await
+            .map_err(|e| format!("Error getting query embedding: {}", e))?;
+        if debug && queries.len() > 1 {
+            print_query_similarities(store, query, &embedding);
+        }
+        if query_embedding.is_empty() {
+            query_embedding = vec![0.0; embedding.len()];
+        }
+        for (combined, value) in query_embedding.iter_mut().zip(embedding.iter()) {
+            *combined += query.weight * value / total_weight;
+        }
+    }
+    if let (Some(hyde_embedding), HydeFusion::Average) = (&hyde_embedding, options.hyde_fusion) {
+        query_embedding = crate::hyde::average_vectors(&query_embedding, hyde_embedding);
+    }
+
+    // Negative queries are scaled relative to the positive weights
+    let mut negative_embeddings: Vec<(f32, Vec<f32>)> = Vec::new();
+    for query in &options.negative_queries {
+        let embedding = embeddings
+            .embed(&query.text, api_key)
+            .await
+            .map_err(|e| format!("Error getting negative query embedding: {}", e))?;
+        if debug {
+            print_query_similarities(store, query, &embedding);
+        }
+        negative_embeddings.push((query.weight / total_weight, embedding));
+    }
+
+    // Calculate similarity for each chunk
+    let mut matches: Vec<FileMatch> = store
+        .rag_vectors
+        .iter()
+        .enumerate()
+        .map(|(chunk_index, embedding)| {
+            let mut similarity =
+                crate::similarity::dot_product_similarity(&query_embedding, &embedding.vector);
+            if let (Some(hyde_embedding), HydeFusion::Max) = (&hyde_embedding, options.hyde_fusion)
+            {
+                similarity = similarity.max(crate::similarity::dot_product_similarity(
+                    hyde_embedding,
+                    &embedding.vector,
+                ));
+            }
+            for (weight, negative_embedding) in &negative_embeddings {
+                similarity -= weight
+                    * crate::similarity::dot_product_similarity(
+                        negative_embedding,
+                        &embedding.vector,
+                    );
+            }
+            FileMatch {
+                filename: embedding.filename.clone(),
+                similarity,
+                chunk_index,
+                expansion: None,
+                components: ScoreComponents::default(),
+            }
+        })
+        .collect();
+
+    // Sort matches by similarity
+    matches.sort_by(|a, b| b.similarity.partial_cmp(&a.similarity).unwrap());
+
+    if debug {
+        //print out the RAG vector distances and filename, in descending
+        println!("RAG distances:");
+        for m in &matches {
+            println!("{} {}", m.similarity, m.filename);
         }
         println!("\n");
     }
@@ -150,23 +491,84 @@ pub async fn find_related_files(
         println!("\n");
     }
 
+    // Recency decays with distance from the as-of date, already in 0-1 range
+    let mut recency_scores: HashMap<String, f32> = HashMap::new();
+    if options.recency_scale != 0.0 {
+        let as_of = match options.recency.as_of {
+            Some(as_of) => as_of,
+            None => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs(),
+        };
+        let timestamps = crate::recency::file_timestamps(store, &options.recency)?;
+        for (filename, timestamp) in &timestamps {
+            let score =
+                crate::recency::recency_score(*timestamp, as_of, options.recency.half_life_days);
+            recency_scores.insert(filename.clone(), score);
+        }
+
+        if debug {
+            println!("Recency scores (as of {}):", as_of);
+            let mut sorted: Vec<_> = recency_scores.iter().collect();
+            sorted.sort_by(|a, b| b.1.partial_cmp(a.1).unwrap());
+            for (filename, score) in sorted {
+                println!(
+                    "{} (+{}) {}",
+                    score,
+                    options.recency_scale * score,
+                    filename
+                );
+            }
+            println!("\n");
+        }
+    }
+
+    // Reciprocal rank fusion only needs the order of each ranking
+    let bm25_ranks = ranks(bm25_scores.iter().map(|(id, score)| (*id, *score)));
+    let recency_ranks = ranks(recency_scores.iter().map(|(f, score)| (f.as_str(), *score)));
+
     // lets add BM25 scores to the matches if there's a file there, or 0
     let mut matches_with_hybrid_scores = matches
         .iter()
-        .map(|m| {
-            let bm25_score = bm25_results
+        .enumerate()
+        .map(|(rag_rank, m)| {
+            let bm25_id = store
+                .bm25_files
                 .iter()
-                .find(|b| {
-                    b.id == store
-                        .bm25_files
-                        .iter()
-                        .position(|f| f.filename == m.filename)
-                        .unwrap() as u32
-                })
-                .map_or(0.0, |b| b.score);
+                .position(|f| f.filename == m.filename)
+                .map(|id| id as u32);
+            let bm25_score = bm25_id
+                .and_then(|id| bm25_scores.get(&id))
+                .copied()
+                .unwrap_or(0.0);
+            let recency_score = recency_scores.get(&m.filename).copied().unwrap_or(0.0);
+            let similarity = match options.fusion {
+                Fusion::Weighted => {
+                    (options.rag_scale * m.similarity)
+                        + (options.bm25_scale * bm25_score)
+                        + (options.recency_scale * recency_score)
+                }
+                Fusion::Rrf => {
+                    let reciprocal =
+                        |rank: Option<&usize>| rank.map_or(0.0, |&r| 1.0 / (RRF_K + r as f32));
+                    (options.rag_scale * reciprocal(Some(&(rag_rank + 1))))
+                        + (options.bm25_scale
+                            * reciprocal(bm25_id.and_then(|id| bm25_ranks.get(&id))))
+                        + (options.recency_scale
+                            * reciprocal(recency_ranks.get(m.filename.as_str())))
+                }
+            };
             FileMatch {
                 filename: m.filename.clone(),
-                similarity: (rag_scale * m.similarity) + (bm25_scale * bm25_score),
+                similarity,
+                chunk_index: m.chunk_index,
+                expansion: None,
+                components: ScoreComponents {
+                    rag: m.similarity,
+                    bm25: bm25_score,
+                    recency: recency_score,
+                    ..Default::default()
+                },
             }
         })
         .collect::<Vec<_>>();
@@ -174,6 +576,61 @@ pub async fn find_related_files(
     // Sort matches by similarity
     matches_with_hybrid_scores.sort_by(|a, b| b.similarity.partial_cmp(&a.similarity).unwrap());
 
+    // Fold in normalized regex match counts, optionally only for the top candidates
+    if !options.boost_regexes.is_empty() && options.regex_scale != 0.0 {
+        let top = if options.boost_regex_top > 0 {
+            options.boost_regex_top
+        } else {
+            usize::MAX
+        };
+        let candidates: Vec<usize> = matches_with_hybrid_scores
+            .iter()
+            .take(top)
+            .map(|m| m.chunk_index)
+            .collect();
+        let counts = crate::regex_boost::count_matches(store, &candidates, &options.boost_regexes);
+        let regex_scores = crate::regex_boost::regex_scores(&counts, &options.boost_regexes);
+        // Under RRF the matching chunks are one more ranking, like BM25 and recency
+        let regex_ranks = ranks(
+            regex_scores
+                .iter()
+                .filter(|(_, &score)| score > 0.0)
+                .map(|(&chunk_index, &score)| (chunk_index, score)),
+        );
+        let boost = |chunk_index: usize| match options.fusion {
+            Fusion::Weighted => options.regex_scale * regex_scores[&chunk_index],
+            Fusion::Rrf => regex_ranks
+                .get(&chunk_index)
+                .map_or(0.0, |&rank| options.regex_scale / (RRF_K + rank as f32)),
+        };
+
+        if debug {
+            let patterns: Vec<&str> = options
+                .boost_regexes
+                .iter()
+                .map(|r| r.regex.as_str())
+                .collect();
+            println!("Regex match counts ({}):", patterns.join(", "));
+            for chunk_index in &candidates {
+                println!(
+                    "{:?} (+{}) {}",
+                    counts[chunk_index],
+                    boost(*chunk_index),
+                    store.rag_vectors[*chunk_index].filename
+                );
+            }
+            println!("\n");
+        }
+
+        for m in &mut matches_with_hybrid_scores {
+            if let Some(score) = regex_scores.get(&m.chunk_index) {
+                m.similarity += boost(m.chunk_index);
+                m.components.regex = *score;
+            }
+        }
+        matches_with_hybrid_scores.sort_by(|a, b| b.similarity.partial_cmp(&a.similarity).unwrap());
+    }
+
     if debug {
         // print out normalized RAG distances
         println!("Hybrid RAG distances:");
@@ -208,29 +665,175 @@ pub async fn find_related_files(
         println!("\n");
     }
 
-    // First filter by similarity threshold
-    let similarity_filtered: Vec<&FileMatch> = matches_with_hybrid_scores
-        .iter()
-        .filter(|m| m.similarity >= filter_similarity)
+    // Weight the final scores by extension, path and test file preferences
+    if !options.preferences.is_empty() || options.test_weight != 1.0 {
+        for m in &mut matches_with_hybrid_scores {
+            m.components.preference = crate::preference::preference_multiplier(
+                &m.filename,
+                &options.preferences,
+                options.test_weight,
+            );
+            m.similarity *= m.components.preference;
+        }
+        matches_with_hybrid_scores.sort_by(|a, b| b.similarity.partial_cmp(&a.similarity).unwrap());
+
+        if debug {
+            println!("Preference weighted distances:");
+            for m in &matches_with_hybrid_scores {
+                let multiplier = crate::preference::preference_multiplier(
+                    &m.filename,
+                    &options.preferences,
+                    options.test_weight,
+                );
+                println!("{} (x{}) {}", m.similarity, multiplier, m.filename);
+            }
+            println!("\n");
+        }
+    }
+
+    // Let the chat model reorder the top candidates
+    if options.rerank > 0 {
+        matches_with_hybrid_scores = crate::rerank::rerank(
+            store,
+            &query_text,
+            matches_with_hybrid_scores,
+            options.rerank,
+            options.rerank_min_grade,
+            api_key,
+            debug,
+        )
+        .await;
+    }
+
+    Ok(matches_with_hybrid_scores)
+}
+
+/// Rank the chunks in `store` against the weighted queries, then filter, deduplicate,
+/// limit and expand them according to `options`
+pub async fn rank_files(
+    store: &FileVectorStore,
+    queries: &[WeightedQuery],
+    api_key: &str,
+    options: &SearchOptions,
+    embeddings: &EmbeddingCache,
+) -> Result<Vec<FileMatch>, Box<dyn std::error::Error>> {
+    let debug = options.debug;
+
+    // Files are scored from their chunks, unless chunks are shown one by one. They are
+    // aggregated before reranking, so the grades order the files and aren't averaged away.
+    let candidates = if !options.verbose && !options.file_contents && options.pack.is_none() {
+        let chunk_options = SearchOptions {
+            rerank: 0,
+            ..options.clone()
+        };
+        let chunks = score_chunks(store, queries, api_key, &chunk_options, embeddings).await?;
+        let files = aggregate_chunks(chunks, options.aggregation);
+        if options.rerank > 0 {
+            crate::rerank::rerank(
+                store,
+                &combined_query_text(queries),
+                files,
+                options.rerank,
+                options.rerank_min_grade,
+                api_key,
+                debug,
+            )
+            .await
+        } else {
+            files
+        }
+    } else {
+        score_chunks(store, queries, api_key, options, embeddings).await?
+    };
+
+    // Then filter by similarity threshold
+    let similarity_filtered: Vec<FileMatch> = candidates
+        .into_iter()
+        .filter(|m| m.similarity >= options.filter_similarity)
         .collect();
 
-    // For non-verbose, non-file-contents mode, deduplicate filenames before count limiting
-    let deduplicated: Vec<&FileMatch> = if !verbose && !file_contents {
-        let mut seen = std::collections::HashSet::new();
+    // Then limit by count if specified
+    let mut final_matches: Vec<FileMatch> = if options.count > 0 {
         similarity_filtered
-            .iter()
-            .filter(|m| seen.insert(&m.filename))
-            .copied()
+            .into_iter()
+            .take(options.count)
             .collect()
     } else {
         similarity_filtered
     };
 
-    // Then limit by count if specified
-    let final_matches: Vec<_> = if count > 0 {
-        deduplicated.iter().take(count).cloned().collect()
-    } else {
-        deduplicated
+    // Add files that tend to change together with the hits
+    if options.expand_cochange > 0 {
+        match crate::cochange::load_graph() {
+            Ok(graph) => crate::expand::expand(
+                store,
+                &mut final_matches,
+                options.expand_cochange,
+                options.cochange_weight,
+                |filename| {
+                    graph
+                        .neighbors(filename)
+                        .into_iter()
+                        .map(|(neighbor, strength)| (neighbor, strength, ExpansionKind::CoChange))
+                        .collect()
+                },
+            ),
+            Err(e) => eprintln!("Skipping co-change expansion: {}", e),
+        }
+    }
+
+    // Add the files the hits import and the files importing them
+    if options.expand_deps > 0 {
+        if store.dependencies.is_empty() {
+            eprintln!("Skipping dependency expansion: no imports in the index, rescan first");
+        } else {
+            crate::expand::expand(
+                store,
+                &mut final_matches,
+                options.expand_deps,
+                options.deps_weight,
+                |filename| crate::deps::neighbors(store, filename),
+            );
+        }
+    }
+
+    if debug && final_matches.iter().any(|m| m.expansion.is_some()) {
+        println!("Expansions:");
+        for m in final_matches.iter().filter(|m| m.expansion.is_some()) {
+            println!(
+                "{} {} ({})",
+                m.similarity,
+                m.filename,
+                m.expansion.as_ref().unwrap()
+            );
+        }
+        println!("\n");
+    }
+
+    Ok(final_matches)
+}
+
+pub async fn find_related_files(
+    queries: &[WeightedQuery],
+    api_key: &str,
+    options: &SearchOptions,
+    embeddings: &EmbeddingCache,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    // Load the vectors file
+    let store = match load_store() {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Ok(Vec::new());
+        }
+    };
+
+    let final_matches = match rank_files(&store, queries, api_key, options, embeddings).await {
+        Ok(matches) => matches,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Ok(Vec::new());
+        }
     };
 
     // Return early if no matches
@@ -239,56 +842,67 @@ pub async fn find_related_files(
     }
 
     // Print results according to flags
-    if verbose {
-        println!("Score,File,Type,Offset,Size");
+    if let Some(pack_options) = &options.pack {
+        let pack = crate::pack::pack(&store, &final_matches, pack_options);
+        print!("{}", pack.text);
+        if !pack.skipped.is_empty() {
+            eprintln!("Didn't fit in {} tokens:", pack_options.max_tokens);
+            for s in &pack.skipped {
+                eprintln!("  {}", s);
+            }
+        }
+    } else if options.verbose {
+        println!("Score,Location,Type,Expansion");
         for m in &final_matches {
-            let embedding = store
-                .rag_vectors
-                .iter()
-                .find(|e| e.filename == m.filename)
-                .unwrap();
+            let embedding = &store.rag_vectors[m.chunk_index];
+            let span = std::fs::read_to_string(&embedding.filename)
+                .ok()
+                .and_then(|contents| crate::span::current_span(&store, embedding, &contents));
+            let location = match span {
+                Some(span) => format!("{}:{}", m.filename, span),
+                None => m.filename.clone(),
+            };
             println!(
-                "{:.3},{},{},{},{}",
+                "{:.3},{},{},{}",
                 m.similarity,
-                m.filename,
+                location,
                 if embedding.is_full_file {
                     "full"
                 } else {
                     "chunk"
                 },
-                embedding.chunk_offset,
-                embedding.chunk_size
+                m.expansion
+                    .as_ref()
+                    .map_or(String::new(), |e| e.to_string())
             );
         }
-    } else if file_contents {
+    } else if options.file_contents {
         for m in &final_matches {
-            let embedding = store
-                .rag_vectors
-                .iter()
-                .find(|e| e.filename == m.filename)
-                .unwrap();
+            let embedding = &store.rag_vectors[m.chunk_index];
 
             if let Ok(contents) = std::fs::read_to_string(&embedding.filename) {
-                let start = embedding.chunk_offset;
-                let end = start + embedding.chunk_size;
-                if end <= contents.len() {
-                    let chunk_content = &contents[start..end];
-
-                    // If metadata was included in the embedding, reconstruct it for display
-                    let display_content = if embedding.has_metadata {
-                        crate::metadata::prepend_metadata(
-                            &embedding.filename,
-                            embedding.last_modified,
-                            std::fs::metadata(&embedding.filename)?.len(),
-                            chunk_content,
-                        )
-                    } else {
-                        chunk_content.to_string()
-                    };
-
-                    println!("\n--- Content from {} ---", embedding.filename);
-                    println!("{}", display_content);
-                    println!("--- End content ---\n");
+                match crate::span::current_span(&store, embedding, &contents) {
+                    Some(span) => {
+                        println!("\n--- {}:{} ---", embedding.filename, span);
+                        // If metadata was included in the embedding, reconstruct it for display
+                        if embedding.has_metadata {
+                            print!(
+                                "{}",
+                                crate::metadata::prepend_metadata(
+                                    &embedding.filename,
+                                    embedding.last_modified,
+                                    contents.len() as u64,
+                                    ""
+                                )
+                            );
+                        }
+                        print!("{}", crate::span::numbered_lines(&contents, span));
+                        println!("--- End content ---\n");
+                    }
+                    None => println!(
+                        "\n--- {} changed since the scan, rescan to locate the chunk ---\n",
+                        embedding.filename
+                    ),
                 }
             }
             println!("{}", m.filename);
@@ -0,0 +1,518 @@
+use crate::cache::{EmbeddingCache, EMBEDDING_CACHE_FILE, MAX_QUERY_EMBEDDINGS};
+use crate::expand::Expansion;
+use crate::scan::{FileVectorStore, RagEmbeddedFileChunk, VECTORS_FILE};
+use crate::search::{FileMatch, ScoreComponents, SearchOptions, WeightedQuery};
+use crate::span::LineSpan;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+use tokio::sync::RwLock;
+
+// Largest files listed by /info
+const INFO_TOP: usize = 10;
+
+/// Turns the ranking knobs of a search request, as command line arguments, into
+/// search options
+pub type OptionsParser = fn(&[String]) -> Result<SearchOptions, String>;
+
+// A file's modification time and size, and the blob id of its contents then
+type FileOid = ((Option<SystemTime>, u64), String);
+
+/// The index as loaded from disk, with the modification time of the vectors file then
+pub struct LoadedIndex {
+    pub store: FileVectorStore,
+    modified: Option<SystemTime>,
+    file_oids: Mutex<HashMap<String, FileOid>>, // Blob ids of result files by path
+}
+
+impl LoadedIndex {
+    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(LoadedIndex {
+            modified: index_modified(),
+            store: crate::search::load_store()?,
+            file_oids: Mutex::default(),
+        })
+    }
+
+    /// The chunk's span in the current contents of its file, like `span::current_span`.
+    /// A file is only read and hashed again when its modification time or size changed,
+    /// so searches of an unchanged tree just stat the result files. Unknown for a file
+    /// that is gone or unreadable.
+    fn current_span(&self, chunk: &RagEmbeddedFileChunk) -> Option<LineSpan> {
+        let metadata = std::fs::metadata(&chunk.filename).ok()?;
+        let stamp = (metadata.modified().ok(), metadata.len());
+        let cached = self
+            .file_oids
+            .lock()
+            .unwrap()
+            .get(&chunk.filename)
+            .filter(|(cached_stamp, _)| *cached_stamp == stamp)
+            .map(|(_, oid)| oid.clone());
+        let mut contents = None;
+        let oid = match cached {
+            Some(oid) => oid,
+            None => {
+                let text = std::fs::read_to_string(&chunk.filename).ok()?;
+                let oid = crate::git::blob_oid(text.as_bytes());
+                self.file_oids
+                    .lock()
+                    .unwrap()
+                    .insert(chunk.filename.clone(), (stamp, oid.clone()));
+                contents = Some(text);
+                oid
+            }
+        };
+        let unchanged = self
+            .store
+            .bm25_files
+            .iter()
+            .any(|f| f.filename == chunk.filename && f.blob_oid == oid);
+        if unchanged && chunk.span.is_known() {
+            return Some(chunk.span);
+        }
+        let contents = match contents {
+            Some(contents) => contents,
+            None => std::fs::read_to_string(&chunk.filename).ok()?,
+        };
+        crate::span::current_span(&self.store, chunk, &contents)
+    }
+
+    /// Whether a scan or another process rewrote the index since it was loaded
+    pub fn is_stale(&self) -> bool {
+        self.modified != index_modified()
+    }
+
+    /// Load the index again. A half-written file fails to parse, then the old index
+    /// keeps serving until the next try.
+    pub fn reload(&mut self) {
+        match Self::load() {
+            Ok(mut index) => {
+                eprintln!("Reloaded {}", VECTORS_FILE);
+                // Blob ids are of the files on disk, they hold for the new index too
+                index.file_oids = std::mem::take(&mut self.file_oids);
+                *self = index;
+            }
+            Err(e) => eprintln!("Keeping the loaded index: {}", e),
+        }
+    }
+
+    /// Write the updated index to disk
+    pub fn save(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        crate::scan::save_store(&self.store)?;
+        self.modified = index_modified();
+        Ok(())
+    }
+}
+
+struct ServerState {
+    index: RwLock<LoadedIndex>,
+    embeddings: EmbeddingCache,
+    token: Option<String>,
+    api_key: String,
+    parse_options: OptionsParser,
+}
+
+// The search futures aren't Send, so connections are served on a local task set
+#[derive(Clone, Copy)]
+struct LocalExec;
+
+impl<F> hyper::rt::Executor<F> for LocalExec
+where
+    F: std::future::Future + 'static,
+{
+    fn execute(&self, future: F) {
+        tokio::task::spawn_local(future);
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchRequest {
+    #[serde(default)]
+    query: Option<String>,
+    #[serde(default)]
+    queries: Vec<String>, // `<prompt>` or `<prompt>^<weight>`
+    #[serde(flatten)]
+    knobs: serde_json::Map<String, Value>, // Ranking options, named like the flags
+}
+
+/// A match located in the current contents of its file
+#[derive(Serialize)]
+pub struct SearchResult<'a> {
+    pub filename: &'a str,
+    pub chunk_index: usize,
+    pub span: Option<LineSpan>, // None when the file is gone or changed and the chunk can't be found
+    pub score: f32,
+    pub components: ScoreComponents,
+    pub expansion: Option<&'a Expansion>,
+}
+
+#[derive(Deserialize)]
+struct FilesRequest {
+    paths: Vec<String>,
+    #[serde(default)]
+    force: bool,
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    let json = serde_json::to_string(body).unwrap_or_else(|_| "{}".to_string());
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: impl std::fmt::Display) -> Response<Body> {
+    json_response(status, &serde_json::json!({ "error": message.to_string() }))
+}
+
+fn index_modified() -> Option<SystemTime> {
+    std::fs::metadata(VECTORS_FILE)
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+async fn reload_if_changed(state: &ServerState) {
+    if !state.index.read().await.is_stale() {
+        return;
+    }
+    let mut index = state.index.write().await;
+    if index.is_stale() {
+        index.reload();
+    }
+}
+
+/// Flags for the ranking knobs: `{"bm25_scale": 0.3, "prefer": ["rs=1.2"], "hyde": true}`
+/// becomes `--bm25-scale=0.3 --prefer=rs=1.2 --hyde`
+pub fn knob_args(knobs: &serde_json::Map<String, Value>) -> Result<Vec<String>, String> {
+    let scalar = |key: &str, value: &Value| match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(format!("invalid value for {}: {}", key, value)),
+    };
+
+    let mut args = Vec::new();
+    for (key, value) in knobs {
+        let flag = format!("--{}", key.replace('_', "-"));
+        match value {
+            Value::Null | Value::Bool(false) => {}
+            Value::Bool(true) => args.push(flag),
+            Value::Array(items) => {
+                for item in items {
+                    args.push(format!("{}={}", flag, scalar(key, item)?));
+                }
+            }
+            value => args.push(format!("{}={}", flag, scalar(key, value)?)),
+        }
+    }
+    Ok(args)
+}
+
+/// The matches as the API returns them, with spans in the files' current contents
+pub fn search_results<'a>(index: &LoadedIndex, matches: &'a [FileMatch]) -> Vec<SearchResult<'a>> {
+    matches
+        .iter()
+        .map(|m| {
+            let span = index.current_span(&index.store.rag_vectors[m.chunk_index]);
+            SearchResult {
+                filename: &m.filename,
+                chunk_index: m.chunk_index,
+                span,
+                score: m.similarity,
+                components: m.components,
+                expansion: m.expansion.as_ref(),
+            }
+        })
+        .collect()
+}
+
+async fn search(state: &ServerState, body: &[u8]) -> Response<Body> {
+    let request: SearchRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+    let mut queries: Vec<WeightedQuery> = request
+        .query
+        .iter()
+        .map(|q| WeightedQuery::new(q))
+        .collect();
+    for query in &request.queries {
+        match crate::search::parse_weighted_query(query) {
+            Ok(query) => queries.push(query),
+            Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+        }
+    }
+    if queries.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "query or queries is required");
+    }
+    let options = match knob_args(&request.knobs).and_then(|args| (state.parse_options)(&args)) {
+        Ok(options) => options,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    let started = Instant::now();
+    let index = state.index.read().await;
+    let store = &index.store;
+    let ranked =
+        crate::search::rank_files(store, &queries, &state.api_key, &options, &state.embeddings)
+            .await;
+    // Only writes the cache when a new query was embedded
+    if let Err(e) = state.embeddings.save() {
+        eprintln!("Error writing embedding cache: {}", e);
+    }
+    let matches = match ranked {
+        Ok(matches) => matches,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+
+    json_response(
+        StatusCode::OK,
+        &serde_json::json!({
+            "results": search_results(&index, &matches),
+            "took_ms": started.elapsed().as_millis(),
+        }),
+    )
+}
+
+// Indexes of a commit can't be updated from the working tree
+fn snapshot_conflict(store: &FileVectorStore) -> Option<Response<Body>> {
+    let rev = store.git_rev.as_ref()?;
+    Some(error_response(
+        StatusCode::CONFLICT,
+        format!("the index was built from commit {}, rescan it instead", rev),
+    ))
+}
+
+async fn update_files(state: &ServerState, body: &[u8], add: bool) -> Response<Body> {
+    let request: FilesRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+    let paths: Vec<String> = request
+        .paths
+        .iter()
+        .map(|p| crate::update::normalize_path(p))
+        .collect();
+
+    let mut not_indexed = Vec::new();
+    let (mut index, summary) = if add {
+        let mut prepared = {
+            let index = state.index.read().await;
+            if let Some(response) = snapshot_conflict(&index.store) {
+                return response;
+            }
+            let unmatched = crate::update::unmatched_paths(&index.store, &paths);
+            if !request.force && !unmatched.is_empty() {
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "not matching the index's pattern \"{}\" (set force to add anyway): {}",
+                        index.store.pattern,
+                        unmatched.join(", ")
+                    ),
+                );
+            }
+            match crate::update::PreparedFiles::read(&index.store, &paths) {
+                Ok(prepared) => prepared,
+                Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+            }
+        };
+        // Searches go on while the files are embedded, the index is only locked to
+        // splice them in
+        if let Err(e) = prepared.embed(&state.api_key).await {
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, e);
+        }
+        let mut index = state.index.write().await;
+        if let Some(response) = snapshot_conflict(&index.store) {
+            return response;
+        }
+        match prepared.apply(&mut index.store) {
+            Ok(summary) => (index, summary),
+            Err(e) => return error_response(StatusCode::CONFLICT, e),
+        }
+    } else {
+        let mut index = state.index.write().await;
+        if let Some(response) = snapshot_conflict(&index.store) {
+            return response;
+        }
+        let mut summary = crate::update::UpdateSummary::default();
+        not_indexed = crate::update::remove_files(&mut index.store, &paths, &mut summary);
+        (index, summary)
+    };
+
+    if summary.changed() {
+        if let Err(e) = index.save() {
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, e);
+        }
+    }
+    json_response(
+        StatusCode::OK,
+        &serde_json::json!({
+            "added": summary.added,
+            "updated": summary.updated,
+            "unchanged": summary.unchanged,
+            "removed": summary.removed,
+            "not_indexed": not_indexed,
+        }),
+    )
+}
+
+fn authorized(state: &ServerState, request: &Request<Body>) -> bool {
+    let Some(token) = &state.token else {
+        return true;
+    };
+    request
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| given == token)
+}
+
+// `localhost` or a loopback address, with or without a port
+fn is_loopback_authority(authority: &str) -> bool {
+    let host = match authority.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+        None => authority.split(':').next().unwrap_or_default(),
+    };
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Without a token, only requests addressed to a loopback host and not sent by a page of
+/// another site are served. Web pages can reach a loopback server too, directly or by
+/// rebinding their own domain to 127.0.0.1, and would otherwise spend the API key.
+fn local_request(state: &ServerState, request: &Request<Body>) -> bool {
+    if state.token.is_some() {
+        return true;
+    }
+    let header = |name: &str| {
+        request
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap_or_default())
+    };
+    let host_ok = header("Host").is_none_or(is_loopback_authority);
+    let origin_ok = header("Origin").is_none_or(|origin| {
+        origin
+            .strip_prefix("http://")
+            .or_else(|| origin.strip_prefix("https://"))
+            .is_some_and(is_loopback_authority)
+    });
+    host_ok && origin_ok
+}
+
+async fn route(state: &ServerState, request: Request<Body>) -> Response<Body> {
+    if !authorized(state, &request) {
+        return error_response(StatusCode::UNAUTHORIZED, "missing or wrong bearer token");
+    }
+    if !local_request(state, &request) {
+        return error_response(
+            StatusCode::FORBIDDEN,
+            "only local requests are served without --token",
+        );
+    }
+    reload_if_changed(state).await;
+
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    match (method, path.as_str()) {
+        (Method::GET, "/health") => {
+            json_response(StatusCode::OK, &serde_json::json!({ "ok": true }))
+        }
+        (Method::GET, "/info") => {
+            let index = state.index.read().await;
+            json_response(
+                StatusCode::OK,
+                &crate::info::index_info(&index.store, INFO_TOP),
+            )
+        }
+        (Method::GET, "/status") => {
+            let index = state.index.read().await;
+            match crate::info::index_status(&index.store) {
+                Ok(changes) => {
+                    let changes: Vec<Value> = changes
+                        .iter()
+                        .map(|(status, filename)| {
+                            serde_json::json!({ "status": status, "filename": filename })
+                        })
+                        .collect();
+                    json_response(StatusCode::OK, &serde_json::json!({ "changes": changes }))
+                }
+                Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+            }
+        }
+        (Method::POST, "/search") => search(state, &body).await,
+        (Method::POST, "/files/add") => update_files(state, &body, true).await,
+        (Method::POST, "/files/remove") => update_files(state, &body, false).await,
+        _ => error_response(StatusCode::NOT_FOUND, format!("no route for {}", path)),
+    }
+}
+
+async fn handle(
+    state: Rc<ServerState>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let started = Instant::now();
+    let description = format!("{} {}", request.method(), request.uri().path());
+    let response = route(&state, request).await;
+    println!(
+        "{} {} {}ms",
+        description,
+        response.status().as_u16(),
+        started.elapsed().as_millis()
+    );
+    Ok(response)
+}
+
+/// Serve the index over HTTP until interrupted, keeping it in memory and reloading it
+/// when the vectors file changes. Anything but a loopback address needs a token.
+pub async fn serve(
+    host: &str,
+    port: u16,
+    token: Option<String>,
+    api_key: String,
+    parse_options: OptionsParser,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ip: IpAddr = host
+        .parse()
+        .map_err(|e| format!("Invalid host {}: {}", host, e))?;
+    if !ip.is_loopback() && token.is_none() {
+        return Err(format!("Serving on {} requires --token", host).into());
+    }
+
+    let state = Rc::new(ServerState {
+        index: RwLock::new(LoadedIndex::load()?),
+        embeddings: EmbeddingCache::load(EMBEDDING_CACHE_FILE, MAX_QUERY_EMBEDDINGS),
+        token,
+        api_key,
+        parse_options,
+    });
+
+    let make_service = make_service_fn(move |_connection| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| handle(state.clone(), request))) }
+    });
+    let address = SocketAddr::new(ip, port);
+    let server = Server::try_bind(&address)?
+        .executor(LocalExec)
+        .serve(make_service);
+    println!("Serving {} on http://{}", VECTORS_FILE, address);
+
+    let local = tokio::task::LocalSet::new();
+    local.run_until(server).await?;
+    Ok(())
+}
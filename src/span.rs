@@ -0,0 +1,180 @@
+use crate::scan::{FileVectorStore, RagEmbeddedFileChunk};
+use serde::{Deserialize, Serialize};
+
+/// 1-based, inclusive line and column span of a chunk. All zero when unknown, as in
+/// indexes scanned before spans were recorded.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct LineSpan {
+    pub start_line: usize,
+    pub start_column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+impl LineSpan {
+    pub fn is_known(&self) -> bool {
+        self.start_line > 0
+    }
+}
+
+impl std::fmt::Display for LineSpan {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start_line, self.end_line)
+    }
+}
+
+// 1-based line and column (in characters) of a byte offset
+fn line_and_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+fn floor_char_boundary(contents: &str, mut offset: usize) -> usize {
+    offset = offset.min(contents.len());
+    while !contents.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+/// Span of the bytes `offset..offset + size` of `contents`, clamped to the contents
+pub fn line_span(contents: &str, offset: usize, size: usize) -> LineSpan {
+    let start = floor_char_boundary(contents, offset);
+    let end = floor_char_boundary(contents, offset + size);
+    let (start_line, start_column) = line_and_column(contents, start);
+    // The last character is inside the span, so step back from the end
+    let last = if end > start {
+        contents[..end]
+            .char_indices()
+            .last()
+            .map_or(start, |(i, _)| i)
+    } else {
+        start
+    };
+    let (end_line, end_column) = line_and_column(contents, last);
+    LineSpan {
+        start_line,
+        start_column,
+        end_line,
+        end_column,
+    }
+}
+
+/// First and last lines of a chunk's text, recorded at scan time so the chunk can be
+/// found again in changed contents without the scanned version of the file. The last
+/// line is empty for a chunk on one line.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ChunkEnds {
+    pub first_line: String,
+    pub last_line: String,
+}
+
+impl ChunkEnds {
+    pub fn of(text: &str) -> Self {
+        let mut lines = text.lines();
+        ChunkEnds {
+            first_line: lines.next().unwrap_or_default().to_string(),
+            last_line: lines.last().unwrap_or_default().to_string(),
+        }
+    }
+
+    /// Offset and size of the chunk in `contents`. The first line has to appear exactly
+    /// once, and of the last line's matches after it the one ending nearest to where a
+    /// chunk of the scanned `size` would end is taken.
+    fn locate(&self, contents: &str, size: usize) -> Option<(usize, usize)> {
+        if self.first_line.trim().is_empty() {
+            return None;
+        }
+        let mut found = contents.match_indices(self.first_line.as_str());
+        let start = match (found.next(), found.next()) {
+            (Some((offset, _)), None) => offset,
+            _ => return None,
+        };
+        let after_first = start + self.first_line.len();
+        if self.last_line.is_empty() {
+            return Some((start, self.first_line.len()));
+        }
+        let end = contents[after_first..]
+            .match_indices(self.last_line.as_str())
+            .map(|(offset, _)| after_first + offset + self.last_line.len())
+            .min_by_key(|end| end.abs_diff(start + size))?;
+        Some((start, end - start))
+    }
+}
+
+// Modification time of a file in seconds, as recorded at scan time
+fn modified_secs(path: &str) -> Option<u64> {
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    Some(
+        modified
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()?
+            .as_secs(),
+    )
+}
+
+/// The chunk's span in the current `contents` of its file. While the file is unchanged
+/// since the scan, the stored span is used. Otherwise the chunk's scanned text is looked
+/// up in the contents, reading the scanned version from git. Without it, a file with an
+/// unknown blob id and its scanned modification time is taken as unchanged, and a changed
+/// chunk is found by its first and last lines. The span is unknown if the chunk is gone
+/// or repeated.
+pub fn current_span(
+    store: &FileVectorStore,
+    chunk: &RagEmbeddedFileChunk,
+    contents: &str,
+) -> Option<LineSpan> {
+    let scanned_oid = store
+        .bm25_files
+        .iter()
+        .find(|f| f.filename == chunk.filename)
+        .map_or("", |f| f.blob_oid.as_str());
+    if !scanned_oid.is_empty() && scanned_oid == crate::git::blob_oid(contents.as_bytes()) {
+        return Some(if chunk.span.is_known() {
+            chunk.span
+        } else {
+            line_span(contents, chunk.chunk_offset, chunk.chunk_size)
+        });
+    }
+
+    let scanned = Some(scanned_oid)
+        .filter(|oid| !oid.is_empty())
+        .and_then(|oid| crate::git::read_blob(oid).ok())
+        .and_then(|blob| String::from_utf8(blob).ok());
+    match scanned {
+        Some(scanned) => {
+            if let Some(text) = chunk.text(&scanned).filter(|text| !text.is_empty()) {
+                let mut found = contents.match_indices(text).map(|(offset, _)| offset);
+                match (found.next(), found.next()) {
+                    (Some(offset), None) => return Some(line_span(contents, offset, text.len())),
+                    (Some(_), Some(_)) => return None,
+                    _ => {}
+                }
+            }
+        }
+        // Indexes from before blob ids were recorded can only tell by the time
+        None if scanned_oid.is_empty()
+            && modified_secs(&chunk.filename) == Some(chunk.last_modified) =>
+        {
+            return Some(line_span(contents, chunk.chunk_offset, chunk.chunk_size));
+        }
+        None => {}
+    }
+    let (offset, size) = chunk.ends.locate(contents, chunk.chunk_size)?;
+    Some(line_span(contents, offset, size))
+}
+
+/// The whole lines covered by `span`, each prefixed with its line number
+pub fn numbered_lines(contents: &str, span: LineSpan) -> String {
+    let width = span.end_line.to_string().len();
+    contents
+        .lines()
+        .enumerate()
+        .skip(span.start_line.saturating_sub(1))
+        .take(span.end_line + 1 - span.start_line.max(1))
+        .map(|(i, line)| format!("{:>width$} | {}\n", i + 1, line, width = width))
+        .collect()
+}
@@ -0,0 +1,462 @@
+use crate::bm25_embedder::create_bm25_vector;
+use crate::bm25_ranker::create_embedding_from_indices_and_values;
+use crate::cache::EmbeddingCache;
+use crate::scan::{FileVectorStore, SourceFile};
+use crate::search::{FileMatch, SearchOptions, WeightedQuery};
+use crate::span::LineSpan;
+use bm25::Scorer;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// How far a definition's header may run before its body or `;`
+const MAX_HEADER_LINES: usize = 20;
+
+/// A function, struct, enum, trait, impl, class or interface definition
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: String,
+    pub filename: String,
+    pub span: LineSpan,
+}
+
+pub struct SymbolMatch {
+    pub symbol_index: usize, // Index in FileVectorStore::symbols
+    pub score: f32,
+}
+
+#[derive(Clone, Copy)]
+enum BodyStyle {
+    Braces,
+    Indentation,
+}
+
+struct Definition {
+    regex: Regex,
+    kind: &'static str,
+}
+
+fn definitions(extension: &str) -> Option<(Vec<Definition>, BodyStyle)> {
+    let definition = |pattern: &str, kind| Definition {
+        regex: Regex::new(pattern).unwrap(),
+        kind,
+    };
+    // Every pattern captures the symbol's name as `name`
+    match extension {
+        "rs" => Some((
+            vec![
+                definition(
+                    r#"^\s*(?:pub(?:\([^)]*\))?\s+)?(?:(?:const|async|unsafe|extern\s+"[^"]*")\s+)*fn\s+(?P<name>[A-Za-z_][A-Za-z0-9_]*)"#,
+                    "function",
+                ),
+                definition(
+                    r"^\s*(?:pub(?:\([^)]*\))?\s+)?struct\s+(?P<name>[A-Za-z_][A-Za-z0-9_]*)",
+                    "struct",
+                ),
+                definition(
+                    r"^\s*(?:pub(?:\([^)]*\))?\s+)?enum\s+(?P<name>[A-Za-z_][A-Za-z0-9_]*)",
+                    "enum",
+                ),
+                definition(
+                    r"^\s*(?:pub(?:\([^)]*\))?\s+)?(?:unsafe\s+)?trait\s+(?P<name>[A-Za-z_][A-Za-z0-9_]*)",
+                    "trait",
+                ),
+                definition(
+                    r"^\s*(?:unsafe\s+)?impl(?:<[^>]*>)?\s+(?P<name>[A-Za-z_][A-Za-z0-9_:<>, ]*?)\s*(?:\{|where\b|$)",
+                    "impl",
+                ),
+            ],
+            BodyStyle::Braces,
+        )),
+        "js" | "jsx" | "mjs" | "cjs" | "ts" | "tsx" => Some((
+            vec![
+                definition(
+                    r"^\s*(?:export\s+)?(?:default\s+)?(?:async\s+)?function\s*\*?\s*(?P<name>[A-Za-z_$][A-Za-z0-9_$]*)",
+                    "function",
+                ),
+                definition(
+                    r"^\s*(?:export\s+)?(?:const|let|var)\s+(?P<name>[A-Za-z_$][A-Za-z0-9_$]*)\s*(?::[^=]+)?=\s*(?:async\s+)?(?:function\b|\([^)]*\)\s*(?::[^=]+)?=>|[A-Za-z_$][A-Za-z0-9_$]*\s*=>)",
+                    "function",
+                ),
+                definition(
+                    r"^\s*(?:export\s+)?(?:default\s+)?(?:abstract\s+)?class\s+(?P<name>[A-Za-z_$][A-Za-z0-9_$]*)",
+                    "class",
+                ),
+                definition(
+                    r"^\s*(?:export\s+)?interface\s+(?P<name>[A-Za-z_$][A-Za-z0-9_$]*)",
+                    "interface",
+                ),
+                definition(
+                    r"^\s*(?:export\s+)?(?:const\s+)?enum\s+(?P<name>[A-Za-z_$][A-Za-z0-9_$]*)",
+                    "enum",
+                ),
+            ],
+            BodyStyle::Braces,
+        )),
+        "py" => Some((
+            vec![
+                definition(
+                    r"^\s*(?:async\s+)?def\s+(?P<name>[A-Za-z_][A-Za-z0-9_]*)",
+                    "function",
+                ),
+                definition(r"^\s*class\s+(?P<name>[A-Za-z_][A-Za-z0-9_]*)", "class"),
+            ],
+            BodyStyle::Indentation,
+        )),
+        "go" => Some((
+            vec![
+                definition(
+                    r"^func\s+(?:\([^)]*\)\s*)?(?P<name>[A-Za-z_][A-Za-z0-9_]*)",
+                    "function",
+                ),
+                definition(
+                    r"^type\s+(?P<name>[A-Za-z_][A-Za-z0-9_]*)\s+struct\b",
+                    "struct",
+                ),
+                definition(
+                    r"^type\s+(?P<name>[A-Za-z_][A-Za-z0-9_]*)\s+interface\b",
+                    "interface",
+                ),
+            ],
+            BodyStyle::Braces,
+        )),
+        _ => None,
+    }
+}
+
+/// Characters of a line outside string and character literals and `//` comments, with
+/// their 1-based columns, so braces in `"{}"` or `'{'` aren't counted
+fn code_chars(line: &str) -> Vec<(usize, char)> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut code = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '"' | '`' => {
+                let quote = chars[i];
+                i += 1;
+                while i < chars.len() && chars[i] != quote {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+            }
+            // A character literal, not a lifetime like 'a
+            '\'' if chars.get(i + 2) == Some(&'\'') => i += 2,
+            '\'' if chars.get(i + 1) == Some(&'\\') => {
+                i += 2;
+                while i < chars.len() && chars[i] != '\'' {
+                    i += 1;
+                }
+            }
+            '/' if chars.get(i + 1) == Some(&'/') => break,
+            c => code.push((i + 1, c)),
+        }
+        i += 1;
+    }
+    code
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+// 0-based index and 1-based column of the line ending the definition starting at `start`
+fn body_end(lines: &[&str], start: usize, style: BodyStyle) -> (usize, usize) {
+    match style {
+        BodyStyle::Braces => {
+            let mut depth = 0;
+            let mut opened = false;
+            for (i, line) in lines.iter().enumerate().skip(start) {
+                for (column, c) in code_chars(line) {
+                    match c {
+                        '{' => {
+                            depth += 1;
+                            opened = true;
+                        }
+                        '}' => depth -= 1,
+                        // Declarations without a body, like `struct Unit;` or trait methods
+                        ';' if !opened => return (i, column),
+                        _ => {}
+                    }
+                    if opened && depth <= 0 {
+                        return (i, column);
+                    }
+                }
+                if !opened && i >= start + MAX_HEADER_LINES {
+                    break;
+                }
+            }
+            (start, lines[start].chars().count())
+        }
+        BodyStyle::Indentation => {
+            let indent = indentation(lines[start]);
+            let mut end = start;
+            for (i, line) in lines.iter().enumerate().skip(start + 1) {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                if indentation(line) <= indent {
+                    break;
+                }
+                end = i;
+            }
+            (end, lines[end].chars().count())
+        }
+    }
+}
+
+/// Symbol definitions in one file, in the order they appear
+pub fn extract_file_symbols(filename: &str, contents: &str) -> Vec<Symbol> {
+    let extension = filename.rsplit_once('.').map_or("", |(_, e)| e);
+    let Some((definitions, style)) = definitions(extension) else {
+        return Vec::new();
+    };
+
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut symbols = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        let Some((captures, kind)) = definitions
+            .iter()
+            .find_map(|d| d.regex.captures(line).map(|c| (c, d.kind)))
+        else {
+            continue;
+        };
+        let (end_line, end_column) = body_end(&lines, i, style);
+        symbols.push(Symbol {
+            name: captures["name"].trim().to_string(),
+            kind: kind.to_string(),
+            filename: filename.to_string(),
+            span: LineSpan {
+                start_line: i + 1,
+                start_column: indentation(line) + 1,
+                end_line: end_line + 1,
+                end_column,
+            },
+        });
+    }
+    symbols
+}
+
+pub fn extract_symbols(files: &[SourceFile]) -> Vec<Symbol> {
+    files
+        .iter()
+        .flat_map(|f| extract_file_symbols(&f.path_str, &f.contents))
+        .collect()
+}
+
+/// The symbol's span in the current `contents` of its file. If the file changed since
+/// the scan, the symbol is looked up again by kind and name.
+pub fn current_span(store: &FileVectorStore, symbol: &Symbol, contents: &str) -> Option<LineSpan> {
+    let unchanged = store.bm25_files.iter().any(|f| {
+        f.filename == symbol.filename
+            && !f.blob_oid.is_empty()
+            && f.blob_oid == crate::git::blob_oid(contents.as_bytes())
+    });
+    if unchanged {
+        return Some(symbol.span);
+    }
+    extract_file_symbols(&symbol.filename, contents)
+        .into_iter()
+        .find(|s| s.kind == symbol.kind && s.name == symbol.name)
+        .map(|s| s.span)
+}
+
+/// Split an identifier into lowercase words, `normalizeBm25Scores` and
+/// `normalize_bm25_scores` both giving "normalize bm25 scores"
+fn identifier_words(name: &str) -> String {
+    let mut words = String::new();
+    let mut previous: Option<char> = None;
+    for c in name.chars() {
+        if !c.is_alphanumeric() {
+            words.push(' ');
+        } else {
+            if c.is_uppercase() && previous.is_some_and(|p| p.is_lowercase()) {
+                words.push(' ');
+            }
+            words.extend(c.to_lowercase());
+        }
+        previous = Some(c);
+    }
+    words
+}
+
+/// BM25 scores of the symbol names against the query, normalized to 0-1
+fn name_scores(symbols: &[Symbol], query_text: &str) -> HashMap<usize, f32> {
+    let documents: Vec<String> = symbols.iter().map(|s| identifier_words(&s.name)).collect();
+    let total_words: usize = documents.iter().map(|d| d.split_whitespace().count()).sum();
+    let avgdl = (total_words as f32 / documents.len().max(1) as f32).max(1.0);
+
+    let mut scorer = Scorer::<u32>::new();
+    for (i, document) in documents.iter().enumerate() {
+        let vector = create_bm25_vector(document, avgdl);
+        scorer.upsert(
+            &(i as u32),
+            create_embedding_from_indices_and_values(vector.indices, vector.values),
+        );
+    }
+    let query = create_bm25_vector(&identifier_words(query_text), avgdl);
+    let matches = scorer.matches(&create_embedding_from_indices_and_values(
+        query.indices,
+        query.values,
+    ));
+
+    let max_score = matches.iter().map(|m| m.score).fold(0.0, f32::max);
+    matches
+        .into_iter()
+        .filter(|_| max_score > 0.0)
+        .map(|m| (m.id as usize, m.score / max_score))
+        .collect()
+}
+
+fn overlaps(a: &LineSpan, b: &LineSpan) -> bool {
+    a.start_line <= b.end_line && b.start_line <= a.end_line
+}
+
+/// Score symbols by the best chunk overlapping them, plus `name_scale` times the BM25
+/// match of their name against the query, best first
+pub fn rank_symbols(
+    store: &FileVectorStore,
+    chunk_matches: &[FileMatch],
+    query_text: &str,
+    name_scale: f32,
+) -> Vec<SymbolMatch> {
+    let mut chunks_by_file: HashMap<&str, Vec<&FileMatch>> = HashMap::new();
+    for m in chunk_matches {
+        chunks_by_file.entry(&m.filename).or_default().push(m);
+    }
+    let names = name_scores(&store.symbols, query_text);
+
+    let mut matches: Vec<SymbolMatch> = store
+        .symbols
+        .iter()
+        .enumerate()
+        .map(|(i, symbol)| {
+            let chunk_score = chunks_by_file
+                .get(symbol.filename.as_str())
+                .into_iter()
+                .flatten()
+                .filter(|m| {
+                    let chunk = &store.rag_vectors[m.chunk_index];
+                    // Old indexes have no chunk spans, count the whole file then
+                    !chunk.span.is_known() || overlaps(&chunk.span, &symbol.span)
+                })
+                .map(|m| m.similarity)
+                .fold(0.0, f32::max);
+            SymbolMatch {
+                symbol_index: i,
+                score: chunk_score + name_scale * names.get(&i).copied().unwrap_or(0.0),
+            }
+        })
+        .collect();
+    matches.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+    matches
+}
+
+/// Look up symbols by name. Exact lookup matches the whole name, fuzzy lookup ranks
+/// case-insensitive equal names, then prefixes, substrings and finally names holding
+/// the query's characters in order.
+pub fn lookup(store: &FileVectorStore, query: &str, exact: bool) -> Vec<SymbolMatch> {
+    let query_lower = query.to_lowercase();
+    let mut matches: Vec<SymbolMatch> = store
+        .symbols
+        .iter()
+        .enumerate()
+        .filter_map(|(i, symbol)| {
+            let score = if exact {
+                (symbol.name == query).then_some(1.0)
+            } else {
+                fuzzy_score(&symbol.name.to_lowercase(), &query_lower)
+            }?;
+            // Shorter names are closer to the query
+            let score = score - symbol.name.len() as f32 / 1000.0;
+            Some(SymbolMatch {
+                symbol_index: i,
+                score,
+            })
+        })
+        .collect();
+    matches.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+    matches
+}
+
+fn fuzzy_score(name: &str, query: &str) -> Option<f32> {
+    if name == query {
+        Some(4.0)
+    } else if name.starts_with(query) {
+        Some(3.0)
+    } else if name.contains(query) {
+        Some(2.0)
+    } else {
+        let mut chars = name.chars();
+        query.chars().all(|q| chars.any(|c| c == q)).then_some(1.0)
+    }
+}
+
+/// Print symbol hits as `path:start-end kind name`, as CSV with scores when verbose,
+/// or with their line-numbered source
+pub fn print_symbol_matches(
+    store: &FileVectorStore,
+    matches: &[SymbolMatch],
+    verbose: bool,
+    file_contents: bool,
+) {
+    if verbose {
+        println!("Score,Location,Kind,Symbol");
+    }
+    for m in matches {
+        let symbol = &store.symbols[m.symbol_index];
+        let contents = std::fs::read_to_string(&symbol.filename).ok();
+        let span = match &contents {
+            Some(contents) => current_span(store, symbol, contents),
+            None => Some(symbol.span),
+        };
+        let Some(span) = span else {
+            // Gone from the file since the scan
+            continue;
+        };
+
+        if verbose {
+            println!(
+                "{:.3},{}:{},{},{}",
+                m.score, symbol.filename, span, symbol.kind, symbol.name
+            );
+        } else {
+            println!(
+                "{}:{} {} {}",
+                symbol.filename, span, symbol.kind, symbol.name
+            );
+        }
+        if file_contents {
+            if let Some(contents) = &contents {
+                print!("{}", crate::span::numbered_lines(contents, span));
+                println!();
+            }
+        }
+    }
+}
+
+/// Rank the indexed symbols against the queries and print the best
+pub async fn find_related_symbols(
+    queries: &[WeightedQuery],
+    api_key: &str,
+    options: &SearchOptions,
+    name_scale: f32,
+    embeddings: &EmbeddingCache,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let store = crate::search::load_store()?;
+    if store.symbols.is_empty() {
+        return Err("No symbols in the index, rescan first".into());
+    }
+
+    let chunk_matches =
+        crate::search::score_chunks(&store, queries, api_key, options, embeddings).await?;
+    let query_text = crate::search::combined_query_text(queries);
+    let mut matches = rank_symbols(&store, &chunk_matches, &query_text, name_scale);
+    matches.retain(|m| m.score >= options.filter_similarity);
+    if options.count > 0 {
+        matches.truncate(options.count);
+    }
+
+    print_symbol_matches(&store, &matches, options.verbose, options.file_contents);
+    Ok(())
+}
@@ -0,0 +1,11 @@
+use std::sync::OnceLock;
+use tiktoken_rs::{cl100k_base, CoreBPE};
+
+static BPE: OnceLock<CoreBPE> = OnceLock::new();
+
+// cl100k_base is the encoding used by the OpenAI embedding and chat models we call
+pub fn count_tokens(text: &str) -> usize {
+    BPE.get_or_init(|| cl100k_base().expect("Failed to load cl100k_base tokenizer"))
+        .encode_with_special_tokens(text)
+        .len()
+}
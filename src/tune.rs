@@ -0,0 +1,194 @@
+use crate::cache::{EmbeddingCache, CHUNK_EMBEDDING_CACHE_FILE};
+use crate::eval::{EvalQuery, Metric, Metrics};
+use crate::random::Random;
+use crate::scan::{FileVectorStore, ScanSource};
+use crate::search::{Aggregation, Fusion, SearchOptions};
+
+const BM25_SCALES: &[f32] = &[0.0, 0.05, 0.1, 0.2, 0.3, 0.5, 1.0];
+const RAG_SCALES: &[f32] = &[0.5, 1.0];
+const FUSIONS: &[Fusion] = &[Fusion::Weighted, Fusion::Rrf];
+const AGGREGATIONS: &[Aggregation] = &[Aggregation::Max, Aggregation::Mean, Aggregation::Sum];
+
+// Number of best configurations listed at the end
+const REPORT_TOP: usize = 5;
+
+/// One point of the search space
+#[derive(Clone, Copy, Debug)]
+pub struct TuneConfig {
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    pub bm25_scale: f32,
+    pub rag_scale: f32,
+    pub fusion: Fusion,
+    pub aggregation: Aggregation,
+}
+
+/// What configurations are ranked by, a metric of the top `k` results
+#[derive(Clone, Copy)]
+pub struct TuneTarget {
+    pub metric: Metric,
+    pub k: usize,
+}
+
+impl std::fmt::Display for TuneConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "chunks {}/{} bm25 {:.2} rag {:.2} {:?} {:?}",
+            self.chunk_size,
+            self.chunk_overlap,
+            self.bm25_scale,
+            self.rag_scale,
+            self.fusion,
+            self.aggregation
+        )
+    }
+}
+
+/// Every combination of the chunkings and the ranking grid
+pub fn grid(chunkings: &[(usize, usize)]) -> Vec<TuneConfig> {
+    let mut configs = Vec::new();
+    for &(chunk_size, chunk_overlap) in chunkings {
+        for &bm25_scale in BM25_SCALES {
+            for &rag_scale in RAG_SCALES {
+                for &fusion in FUSIONS {
+                    for &aggregation in AGGREGATIONS {
+                        configs.push(TuneConfig {
+                            chunk_size,
+                            chunk_overlap,
+                            bm25_scale,
+                            rag_scale,
+                            fusion,
+                            aggregation,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    configs
+}
+
+/// `count` random configurations, with scales drawn from the grid's ranges
+pub fn random(chunkings: &[(usize, usize)], count: usize, seed: u64) -> Vec<TuneConfig> {
+    let mut random = Random::new(seed);
+    (0..count)
+        .map(|_| {
+            let (chunk_size, chunk_overlap) = random.pick(chunkings);
+            TuneConfig {
+                chunk_size,
+                chunk_overlap,
+                bm25_scale: random.range(0.0, 1.0),
+                rag_scale: random.range(0.25, 1.0),
+                fusion: random.pick(FUSIONS),
+                aggregation: random.pick(AGGREGATIONS),
+            }
+        })
+        .collect()
+}
+
+/// Evaluate every configuration on the dataset, best first. Chunkings other than the
+/// index's are scanned into memory, with chunk embeddings from the chunk cache, and
+/// query embeddings come from the query cache.
+pub async fn tune(
+    store: &FileVectorStore,
+    dataset: &[EvalQuery],
+    api_key: &str,
+    base: &SearchOptions,
+    configs: &[TuneConfig],
+    target: TuneTarget,
+    embeddings: &EmbeddingCache,
+) -> Result<Vec<(TuneConfig, Metrics)>, Box<dyn std::error::Error>> {
+    let TuneTarget { metric, k } = target;
+    let mut chunkings: Vec<(usize, usize)> = configs
+        .iter()
+        .map(|c| (c.chunk_size, c.chunk_overlap))
+        .collect();
+    chunkings.sort();
+    chunkings.dedup();
+
+    let chunk_cache = EmbeddingCache::load(CHUNK_EMBEDDING_CACHE_FILE, 0);
+    let mut results = Vec::new();
+    for (chunk_size, chunk_overlap) in chunkings {
+        let rescanned;
+        let chunked_store = if (chunk_size, chunk_overlap) == (store.chunk_size, store.overlap_size)
+        {
+            store
+        } else {
+            println!(
+                "Scanning {} with chunk size {} and overlap {}",
+                store.pattern, chunk_size, chunk_overlap
+            );
+            let source = match &store.git_rev {
+                Some(rev) => ScanSource::GitRev(rev.clone()),
+                None => ScanSource::Walk,
+            };
+            let built = crate::scan::build_store(
+                &store.pattern,
+                api_key,
+                chunk_size,
+                chunk_overlap,
+                store.embed_metadata,
+                &source,
+                Some(&chunk_cache),
+            )
+            .await;
+            // Keep what was paid for even if the scan failed halfway
+            chunk_cache.save()?;
+            rescanned = built?;
+            &rescanned
+        };
+
+        for config in configs
+            .iter()
+            .filter(|c| (c.chunk_size, c.chunk_overlap) == (chunk_size, chunk_overlap))
+        {
+            let options = SearchOptions {
+                bm25_scale: config.bm25_scale,
+                rag_scale: config.rag_scale,
+                fusion: config.fusion,
+                aggregation: config.aggregation,
+                ..base.clone()
+            };
+            let metrics =
+                crate::eval::evaluate(chunked_store, dataset, api_key, &options, k, embeddings)
+                    .await?;
+            let mean = Metrics::mean(&metrics);
+            println!("{}  {:?}@{} {:.3}", config, metric, k, metric.of(&mean));
+            results.push((*config, mean));
+        }
+    }
+
+    results.sort_by(|a, b| metric.of(&b.1).partial_cmp(&metric.of(&a.1)).unwrap());
+    Ok(results)
+}
+
+pub fn print_best(results: &[(TuneConfig, Metrics)], k: usize) {
+    println!(
+        "\nBest of {} configurations (R@{k}, P@{k}, MRR, nDCG@{k}):",
+        results.len(),
+        k = k
+    );
+    for (config, m) in results.iter().take(REPORT_TOP) {
+        println!(
+            "{:.3} {:.3} {:.3} {:.3}  {}",
+            m.recall, m.precision, m.mrr, m.ndcg, config
+        );
+    }
+}
+
+/// Write the configuration to the project config, where the ranking options and scan
+/// pick it up as their defaults
+pub fn write_config(config: &TuneConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let mut values = serde_json::Map::new();
+    values.insert("bm25_scale".to_string(), config.bm25_scale.into());
+    values.insert("rag_scale".to_string(), config.rag_scale.into());
+    values.insert("fusion".to_string(), serde_json::to_value(config.fusion)?);
+    values.insert(
+        "aggregation".to_string(),
+        serde_json::to_value(config.aggregation)?,
+    );
+    values.insert("chunk_size".to_string(), config.chunk_size.into());
+    values.insert("chunk_overlap".to_string(), config.chunk_overlap.into());
+    crate::config::update_config(values)
+}
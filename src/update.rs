@@ -0,0 +1,313 @@
+use crate::bm25_embedder::create_bm25_vector_from_tokens;
+use crate::scan::{FileVectorStore, SourceFile};
+use glob_match::glob_match;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Component, Path};
+use std::process::{Command, Stdio};
+
+/// What an in-place update changed
+#[derive(Default)]
+pub struct UpdateSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+impl UpdateSummary {
+    pub fn changed(&self) -> bool {
+        self.added + self.updated + self.removed > 0
+    }
+}
+
+impl std::fmt::Display for UpdateSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} added, {} updated, {} unchanged, {} removed",
+            self.added, self.updated, self.unchanged, self.removed
+        )
+    }
+}
+
+/// The index, for editing in place. Snapshots of a commit can only be rescanned, their
+/// contents come from git rather than the working tree.
+pub fn load_store_for_update() -> Result<FileVectorStore, Box<dyn std::error::Error>> {
+    let store = crate::search::load_store()?;
+    if let Some(rev) = &store.git_rev {
+        return Err(format!(
+            "The index was built from commit {}, rescan it with --rev instead",
+            rev
+        )
+        .into());
+    }
+    Ok(store)
+}
+
+/// A path as the index stores it: relative to the current directory, with `/`
+/// separators and no `.` or `..` components
+pub fn normalize_path(path: &str) -> String {
+    let path = Path::new(path);
+    let current_dir = std::env::current_dir().unwrap_or_default();
+    let relative = path.strip_prefix(&current_dir).unwrap_or(path);
+    let mut parts: Vec<String> = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().to_string()),
+            Component::ParentDir => {
+                parts.pop();
+            }
+            _ => {}
+        }
+    }
+    parts.join("/")
+}
+
+/// Paths that don't match the pattern the index was scanned with
+pub fn unmatched_paths<'a>(store: &FileVectorStore, paths: &'a [String]) -> Vec<&'a str> {
+    paths
+        .iter()
+        .filter(|p| !glob_match(&store.pattern, p))
+        .map(|p| p.as_str())
+        .collect()
+}
+
+// Drop every trace of the files from the store
+fn drop_files(store: &mut FileVectorStore, filenames: &HashSet<&str>) {
+    store
+        .bm25_files
+        .retain(|f| !filenames.contains(f.filename.as_str()));
+    store
+        .rag_vectors
+        .retain(|c| !filenames.contains(c.filename.as_str()));
+    store
+        .symbols
+        .retain(|s| !filenames.contains(s.filename.as_str()));
+}
+
+/// Recompute what depends on the whole corpus after files were added or removed: the
+/// document count, the average document length and every BM25 vector built with it
+fn refresh_corpus(store: &mut FileVectorStore) {
+    store.doc_count = store.bm25_files.len();
+    let total_tokens: usize = store.bm25_files.iter().map(|f| f.token_count).sum();
+    store.bm25_avgdl = total_tokens as f32 / store.doc_count.max(1) as f32;
+    for file in &mut store.bm25_files {
+        let vector = create_bm25_vector_from_tokens(&file.tokens, store.bm25_avgdl);
+        file.bm25_indices = vector.indices;
+        file.bm25_values = vector.values;
+    }
+}
+
+// Drop the files from the import graph, and the edges of other files to them
+fn drop_dependencies(store: &mut FileVectorStore, filenames: &HashSet<&str>) {
+    store
+        .dependencies
+        .retain(|filename, _| !filenames.contains(filename.as_str()));
+    for dependencies in store.dependencies.values_mut() {
+        dependencies.retain(|d| !filenames.contains(d.as_str()));
+    }
+    store
+        .dependencies
+        .retain(|_, dependencies| !dependencies.is_empty());
+}
+
+/// Replace the import edges of the changed files. Unchanged files only gain edges when
+/// a file they import joins the index, and Go imports all resolve against go.mod, so
+/// the graph is rebuilt from the indexed files on disk then.
+fn refresh_dependencies(store: &mut FileVectorStore, changed: &[SourceFile], rebuild: bool) {
+    if rebuild || changed.iter().any(|f| f.path_str == "go.mod") {
+        let files: Vec<SourceFile> = store
+            .bm25_files
+            .iter()
+            .filter_map(|f| {
+                crate::scan::read_working_file(Path::new(&f.filename), f.filename.clone()).ok()
+            })
+            .collect();
+        store.dependencies = crate::deps::dependency_graph(&files);
+        return;
+    }
+    let indexed: HashSet<&str> = store
+        .bm25_files
+        .iter()
+        .map(|f| f.filename.as_str())
+        .collect();
+    let updated = crate::deps::file_dependencies(changed, &indexed);
+    for file in changed {
+        store.dependencies.remove(&file.path_str);
+    }
+    store.dependencies.extend(updated);
+}
+
+/// Changed files read for an update, and their entries once embedded. Preparing and
+/// embedding only read the index, so a server can keep searching it meanwhile and lock
+/// it just for `apply`.
+pub struct PreparedFiles {
+    summary: UpdateSummary,
+    changed: Vec<SourceFile>,
+    indexed: FileVectorStore, // Entries of the changed files, chunked like the index
+}
+
+impl PreparedFiles {
+    /// Read the files and find the ones new or changed since they were indexed
+    pub fn read(
+        store: &FileVectorStore,
+        paths: &[String],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut summary = UpdateSummary::default();
+        let mut changed = Vec::new();
+        let mut seen = HashSet::new();
+        for path in paths.iter().filter(|p| seen.insert(p.as_str())) {
+            let file = crate::scan::read_working_file(Path::new(path), path.clone())
+                .map_err(|e| format!("Error reading {}: {}", path, e))?;
+            match store.bm25_files.iter().find(|f| f.filename == *path) {
+                Some(indexed)
+                    if !indexed.blob_oid.is_empty()
+                        && indexed.blob_oid == file.blob_oid
+                        && (!store.embed_metadata
+                            || indexed.last_modified == file.last_modified) =>
+                {
+                    println!("Unchanged: {}", path);
+                    summary.unchanged += 1;
+                }
+                Some(_) => {
+                    summary.updated += 1;
+                    changed.push(file);
+                }
+                None => {
+                    summary.added += 1;
+                    changed.push(file);
+                }
+            }
+        }
+        let indexed = FileVectorStore {
+            rag_vectors: Vec::new(),
+            bm25_files: Vec::new(),
+            pattern: store.pattern.clone(),
+            chunk_size: store.chunk_size,
+            overlap_size: store.overlap_size,
+            embed_metadata: store.embed_metadata,
+            date: store.date,
+            bm25_avgdl: store.bm25_avgdl,
+            doc_count: 0,
+            git_rev: None,
+            dependencies: Default::default(),
+            symbols: Vec::new(),
+        };
+        Ok(PreparedFiles {
+            summary,
+            changed,
+            indexed,
+        })
+    }
+
+    /// Embed the changed files
+    pub async fn embed(&mut self, api_key: &str) -> Result<(), Box<dyn std::error::Error>> {
+        // Vectors are computed with the old average length, refresh_corpus fixes them up
+        for file in &self.changed {
+            crate::scan::index_file(file, &mut self.indexed, None, api_key, None).await?;
+            self.indexed
+                .symbols
+                .extend(crate::symbols::extract_file_symbols(
+                    &file.path_str,
+                    &file.contents,
+                ));
+        }
+        Ok(())
+    }
+
+    /// Replace the changed files' entries in the store with the embedded ones. Fails if
+    /// the store is chunked differently than when the files were read, as after a rescan.
+    pub fn apply(self, store: &mut FileVectorStore) -> Result<UpdateSummary, String> {
+        let PreparedFiles {
+            summary,
+            changed,
+            indexed,
+        } = self;
+        if (store.chunk_size, store.overlap_size, store.embed_metadata)
+            != (
+                indexed.chunk_size,
+                indexed.overlap_size,
+                indexed.embed_metadata,
+            )
+        {
+            return Err("The index was rescanned with other settings during the update".into());
+        }
+        if changed.is_empty() {
+            return Ok(summary);
+        }
+        let filenames: HashSet<&str> = changed.iter().map(|f| f.path_str.as_str()).collect();
+        drop_files(store, &filenames);
+        store.bm25_files.extend(indexed.bm25_files);
+        store.rag_vectors.extend(indexed.rag_vectors);
+        store.symbols.extend(indexed.symbols);
+        refresh_corpus(store);
+        refresh_dependencies(store, &changed, summary.added > 0);
+        Ok(summary)
+    }
+}
+
+/// Index the files, replacing their entries if they were indexed before. Files whose
+/// contents are unchanged since they were indexed are left alone.
+pub async fn add_files(
+    store: &mut FileVectorStore,
+    paths: &[String],
+    api_key: &str,
+) -> Result<UpdateSummary, Box<dyn std::error::Error>> {
+    let mut prepared = PreparedFiles::read(store, paths)?;
+    prepared.embed(api_key).await?;
+    Ok(prepared.apply(store)?)
+}
+
+/// Remove the files from the index, returning the paths that weren't indexed
+pub fn remove_files(
+    store: &mut FileVectorStore,
+    paths: &[String],
+    summary: &mut UpdateSummary,
+) -> Vec<String> {
+    let (indexed, missing): (Vec<&String>, Vec<&String>) = paths
+        .iter()
+        .partition(|p| store.bm25_files.iter().any(|f| f.filename == **p));
+    if !indexed.is_empty() {
+        let filenames: HashSet<&str> = indexed.iter().map(|p| p.as_str()).collect();
+        drop_files(store, &filenames);
+        refresh_corpus(store);
+        drop_dependencies(store, &filenames);
+        summary.removed += indexed.len();
+    }
+    missing.into_iter().cloned().collect()
+}
+
+/// Run `scan --stdin` on the paths in a child process and return its summary, for
+/// servers whose stdout carries a protocol that indexing progress would corrupt
+pub fn rescan_in_child(
+    paths: &[String],
+    force: bool,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut command = Command::new(std::env::current_exe()?);
+    command.args(["scan", "--stdin"]);
+    if force {
+        command.arg("--force");
+    }
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        writeln!(stdin, "{}", paths.join("\n"))?;
+    }
+    let output = child.wait_with_output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprint!("{}", stderr);
+    if !output.status.success() {
+        return Err(format!("Rescan failed: {}", stderr.trim()).into());
+    }
+    // The summary and any skipped files, without the per-file progress
+    Ok(stdout
+        .lines()
+        .filter(|line| !line.starts_with("Processing: ") && !line.starts_with("Got embedding"))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}